@@ -0,0 +1,384 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::env;
+use std::fs::{self, File};
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use opengl_graphics::Texture;
+use tiled;
+
+use world;
+use audio;
+use pcm::{self, SoundData, SoundFormat};
+use error::{Error, Result};
+
+/// Environment variable that overrides where assets are loaded from
+pub const ASSET_ROOT_VAR: &'static str = "SRE_ASSETS";
+
+///
+/// Typed reference to a loaded asset, only valid for the AssetManager that returned it
+///
+pub struct Handle<T> {
+    index: usize,
+    marker: PhantomData<T>,
+}
+
+impl<T> Clone for Handle<T> {
+    fn clone(&self) -> Handle<T> {
+        Handle { index: self.index, marker: PhantomData }
+    }
+}
+
+impl<T> Copy for Handle<T> {}
+
+impl<T> PartialEq for Handle<T> {
+    fn eq(&self, other: &Handle<T>) -> bool {
+        self.index == other.index
+    }
+}
+
+///
+/// A texture that's swapped in place when its file is hot reloaded. Definitions
+/// that hand their texture out to sprites they spawn hold one of these.
+///
+#[derive(Clone)]
+pub struct SharedTexture(Rc<RefCell<Rc<Texture>>>);
+
+impl SharedTexture {
+    pub fn get(&self) -> Rc<Texture> {
+        self.0.borrow().clone()
+    }
+}
+
+///
+/// A decoded sound, uploaded to the audio backend
+///
+pub struct Sound {
+    pub id: audio::SoundId,
+    pub duration: f32,
+}
+
+struct Entry<T> {
+    name: String,
+    path: PathBuf,
+    modified: u64,
+    asset: Rc<T>,
+}
+
+///
+/// Assets of one type, deduplicated by logical name
+///
+struct Store<T> {
+    entries: Vec<Entry<T>>,
+    names: HashMap<String, usize>,
+}
+
+impl<T> Store<T> {
+
+    fn new() -> Store<T> {
+        Store {
+            entries: Vec::new(),
+            names: HashMap::new(),
+        }
+    }
+
+    fn find(&self, name: &str) -> Option<Handle<T>> {
+        self.names.get(name).map(|&index| Handle { index: index, marker: PhantomData })
+    }
+
+    fn insert(&mut self, name: &str, path: PathBuf, asset: T) -> Handle<T> {
+        let index = self.entries.len();
+        self.entries.push(Entry {
+            name: name.to_string(),
+            modified: modified_time(&path),
+            path: path,
+            asset: Rc::new(asset),
+        });
+        self.names.insert(name.to_string(), index);
+        Handle { index: index, marker: PhantomData }
+    }
+
+    fn get(&self, handle: Handle<T>) -> Rc<T> {
+        self.entries[handle.index].asset.clone()
+    }
+}
+
+///
+/// Loads textures, sounds and maps by logical name, relative to the asset root,
+/// e.g. `"player.png"`. Each file is only loaded once and shared through handles.
+///
+/// With `hot_reload` set, `reload_changed` picks up files modified on disk.
+///
+pub struct AssetManager {
+    root: PathBuf,
+    audio: audio::SharedBackend,
+    textures: Store<Texture>,
+    /// Handed out by `shared_texture`, by texture handle index
+    shared_textures: HashMap<usize, SharedTexture>,
+    sounds: Store<Sound>,
+    maps: Store<tiled::Map>,
+    pub hot_reload: bool,
+    /// Number of files actually loaded from disk, including reloads
+    pub loads: usize,
+}
+
+impl AssetManager {
+
+    pub fn new(audio: audio::SharedBackend) -> AssetManager {
+        AssetManager::with_root(find_asset_root(), audio)
+    }
+
+    pub fn with_root(root: PathBuf, audio: audio::SharedBackend) -> AssetManager {
+        println!("Loading assets from {}", root.display());
+        AssetManager {
+            root: root,
+            audio: audio,
+            textures: Store::new(),
+            shared_textures: HashMap::new(),
+            sounds: Store::new(),
+            maps: Store::new(),
+            hot_reload: cfg!(debug_assertions),
+            loads: 0,
+        }
+    }
+
+    ///
+    /// Full path of the asset with the given logical name
+    ///
+    pub fn path(&self, name: &str) -> PathBuf {
+        self.root.join(name)
+    }
+
+    pub fn load_texture(&mut self, name: &str) -> Result<Handle<Texture>> {
+
+        if let Some(handle) = self.textures.find(name) {
+            return Ok(handle);
+        }
+
+        let path = self.path(name);
+        let texture = try!(read_texture(&path));
+        self.loads += 1;
+        Ok(self.textures.insert(name, path, texture))
+    }
+
+    pub fn texture(&self, handle: Handle<Texture>) -> Rc<Texture> {
+        self.textures.get(handle)
+    }
+
+    ///
+    /// The texture for definitions to hold, which follows hot reloads
+    ///
+    pub fn shared_texture(&mut self, handle: Handle<Texture>) -> SharedTexture {
+        let texture = self.textures.get(handle);
+        self.shared_textures.entry(handle.index).or_insert(SharedTexture(Rc::new(RefCell::new(texture)))).clone()
+    }
+
+    ///
+    /// Register a texture under `name` as if it had been loaded,
+    /// tests have no GL context to upload real ones to
+    ///
+    #[cfg(test)]
+    pub fn insert_texture(&mut self, name: &str, texture: Texture) -> Handle<Texture> {
+        let path = self.path(name);
+        self.textures.insert(name, path, texture)
+    }
+
+    pub fn load_sound(&mut self, name: &str) -> Result<Handle<Sound>> {
+
+        if let Some(handle) = self.sounds.find(name) {
+            return Ok(handle);
+        }
+
+        let path = self.path(name);
+        let data = try!(read_sound(&path));
+        let id = self.audio.borrow_mut().create_sound(name, &data);
+        self.loads += 1;
+        Ok(self.sounds.insert(name, path, Sound { id: id, duration: data.duration() }))
+    }
+
+    pub fn sound(&self, handle: Handle<Sound>) -> Rc<Sound> {
+        self.sounds.get(handle)
+    }
+
+    pub fn load_map(&mut self, name: &str) -> Result<Handle<tiled::Map>> {
+
+        if let Some(handle) = self.maps.find(name) {
+            return Ok(handle);
+        }
+
+        let path = self.path(name);
+        let map = try!(read_map(&path));
+        self.loads += 1;
+        Ok(self.maps.insert(name, path, map))
+    }
+
+    pub fn map(&self, handle: Handle<tiled::Map>) -> Rc<tiled::Map> {
+        self.maps.get(handle)
+    }
+
+    ///
+    /// Reload any files that changed on disk since they were loaded.
+    ///
+    /// Sounds are re-uploaded under their existing ids so every holder hears the change.
+    /// Textures are replaced, and sprites in the world or the projectile pool still using the old one
+    /// are pointed at the new one. Shared textures held by definitions are swapped in place.
+    /// Maps are only picked up by the next `load_world`. Returns the names of reloaded assets.
+    ///
+    pub fn reload_changed(&mut self, components: &mut world::Components, entities: &Vec<world::Entity>) -> Vec<String> {
+
+        let mut reloaded = Vec::new();
+
+        if !self.hot_reload {
+            return reloaded;
+        }
+
+        for (index, entry) in self.textures.entries.iter_mut().enumerate() {
+            let modified = modified_time(&entry.path);
+            if modified == entry.modified {
+                continue;
+            }
+            entry.modified = modified;
+
+            match read_texture(&entry.path) {
+                Ok(texture) => {
+                    let old = entry.asset.clone();
+                    entry.asset = Rc::new(texture);
+                    replace_texture(&old, &entry.asset, components, entities);
+                    if let Some(shared) = self.shared_textures.get(&index) {
+                        *shared.0.borrow_mut() = entry.asset.clone();
+                    }
+                    reloaded.push(entry.name.clone());
+                },
+                Err(e) => println!("Couldn't reload {}: {}", entry.name, e),
+            }
+        }
+
+        for entry in self.sounds.entries.iter_mut() {
+            let modified = modified_time(&entry.path);
+            if modified == entry.modified {
+                continue;
+            }
+            entry.modified = modified;
+
+            // Sounds are referred to by id, so replacing the samples updates every holder
+            match read_sound(&entry.path) {
+                Ok(data) => {
+                    self.audio.borrow_mut().update_sound(entry.asset.id, &data);
+                    entry.asset = Rc::new(Sound { id: entry.asset.id, duration: data.duration() });
+                    reloaded.push(entry.name.clone());
+                },
+                Err(e) => println!("Couldn't reload {}: {}", entry.name, e),
+            }
+        }
+
+        for entry in self.maps.entries.iter_mut() {
+            let modified = modified_time(&entry.path);
+            if modified == entry.modified {
+                continue;
+            }
+            entry.modified = modified;
+
+            match read_map(&entry.path) {
+                Ok(map) => {
+                    entry.asset = Rc::new(map);
+                    reloaded.push(entry.name.clone());
+                },
+                Err(e) => println!("Couldn't reload {}: {}", entry.name, e),
+            }
+        }
+
+        self.loads += reloaded.len();
+        reloaded
+    }
+}
+
+///
+/// Asset root, so the game can be run from any working directory:
+/// `$SRE_ASSETS` if set, otherwise the nearest `assets` directory
+/// beside the executable or one of its parents (covers `target/debug`),
+/// falling back to `./assets`.
+///
+pub fn find_asset_root() -> PathBuf {
+
+    if let Some(root) = env::var_os(ASSET_ROOT_VAR) {
+        return PathBuf::from(root);
+    }
+
+    if let Ok(exe) = env::current_exe() {
+        let mut dir = exe.parent();
+        while let Some(current) = dir {
+            let candidate = current.join("assets");
+            if fs::metadata(&candidate).map(|m| m.is_dir()).unwrap_or(false) {
+                return candidate;
+            }
+            dir = current.parent();
+        }
+    }
+
+    PathBuf::from("./assets")
+}
+
+/// Modification time in milliseconds, 0 if it can't be read
+fn modified_time(path: &Path) -> u64 {
+    fs::metadata(path).map(|m| m.modified()).unwrap_or(0)
+}
+
+///
+/// Report missing files as I/O errors before handing them to decoders,
+/// which tend to describe them less clearly
+///
+fn check_exists(path: &Path) -> Result<()> {
+    fs::metadata(path)
+        .map(|_| ())
+        .map_err(|e| Error::Io(path.display().to_string(), e))
+}
+
+fn read_texture(path: &Path) -> Result<Texture> {
+    try!(check_exists(path));
+    Texture::from_path(path).map_err(|e| Error::Image(path.display().to_string(), e))
+}
+
+fn read_sound(path: &Path) -> Result<SoundData> {
+    try!(check_exists(path));
+    pcm::decode_as(&path.to_string_lossy()[..], SoundFormat::effect())
+}
+
+fn read_map(path: &Path) -> Result<tiled::Map> {
+    let file = try!(File::open(path).map_err(|e| Error::Io(path.display().to_string(), e)));
+    tiled::parse(file).map_err(|e| Error::Map(path.display().to_string(), format!("{:?}", e)))
+}
+
+pub fn same_texture(a: &Rc<Texture>, b: &Rc<Texture>) -> bool {
+    &**a as *const Texture == &**b as *const Texture
+}
+
+fn replace_sprite_texture(sprite_renderer: &mut world::SpriteRenderer, old: &Rc<Texture>, new: &Rc<Texture>) {
+    let sprite = &mut sprite_renderer.sprite;
+    if same_texture(sprite.get_texture(), old) {
+        sprite.set_texture(new.clone());
+    }
+}
+
+fn replace_texture(old: &Rc<Texture>, new: &Rc<Texture>, components: &mut world::Components, entities: &Vec<world::Entity>) {
+    for entity in entities.iter() {
+        if let Some(sprite_id) = entity.sprite_renderer {
+            replace_sprite_texture(components.sprite_renderer.get_mut(sprite_id), old, new);
+        }
+        // Bullets parked in the pool aren't in the world until they're fired again
+        if let Some(pool_id) = entity.projectile_pool {
+            for bullet in components.projectile_pool.get(pool_id).free.iter() {
+                if let Some(sprite_id) = bullet.sprite_renderer {
+                    replace_sprite_texture(components.sprite_renderer.get_mut(sprite_id), old, new);
+                }
+            }
+        }
+        if let Some(destructible_id) = entity.destructible {
+            for texture in components.destructible.get_mut(destructible_id).damaged_textures.iter_mut() {
+                if same_texture(texture, old) {
+                    *texture = new.clone();
+                }
+            }
+        }
+    }
+}
@@ -1,94 +1,532 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::env;
+use std::num::Float;
+use std::rc::Rc;
 use graphics::Context;
 use openal::al;
 use openal::alc;
 use opengl_graphics::GlGraphics;
-use sndfile::{SndFile, OpenMode};
 
 use world;
+use mixer::{Mixer, SoundParams};
+use error::{Error, Result};
+use pcm::SoundData;
 
-const READ_BUFFER_SIZE: usize = 4096;
+/// Set to "silent" to run without opening an audio device
+pub const AUDIO_BACKEND_VAR: &'static str = "SRE_AUDIO";
 
-impl world::AudioSource {
+/// A sound uploaded to the backend
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct SoundId(pub usize);
 
-    pub fn new() -> world::AudioSource {
-        world::AudioSource {
-            source: al::Source::gen(),
-            buffer: al::Buffer::gen(),
+/// Something that can play one sound at a time, e.g. an OpenAL source
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct VoiceId(pub usize);
+
+///
+/// Where sounds actually go. The AudioSystem and AssetManager share one,
+/// chosen at startup by `open_backend`.
+///
+pub trait AudioBackend {
+    fn create_sound(&mut self, name: &str, data: &SoundData) -> SoundId;
+    /// Replace a sound's samples in place, e.g. when hot reloading
+    fn update_sound(&mut self, sound: SoundId, data: &SoundData);
+
+    fn create_voice(&mut self) -> VoiceId;
+
+    /// Stop whatever the voice is playing and start the sound
+    fn play(&mut self, voice: VoiceId, sound: SoundId);
+    fn stop(&mut self, voice: VoiceId);
+    /// Stop every voice, used when the world is thrown away
+    fn stop_all(&mut self);
+    fn pause(&mut self, voice: VoiceId);
+    /// Only resumes voices that were paused, not ones that finished
+    fn resume(&mut self, voice: VoiceId);
+    fn is_playing(&self, voice: VoiceId) -> bool;
+
+    fn set_gain(&mut self, voice: VoiceId, gain: f32);
+    /// -1.0 is fully left, 1.0 fully right. Only mono sounds can be panned.
+    fn set_pan(&mut self, voice: VoiceId, pan: f32);
+    fn set_looping(&mut self, voice: VoiceId, looping: bool);
+
+    /// Append interleaved samples to a streaming voice's queue
+    fn queue_samples(&mut self, voice: VoiceId, samples: &[i16], channels: u32, sample_rate: u32);
+    /// Recycle buffers the voice has finished with, returning how many are still queued
+    fn unqueue_processed(&mut self, voice: VoiceId) -> usize;
+    /// Start playing whatever is queued, also used to recover from running dry
+    fn play_queued(&mut self, voice: VoiceId);
+    /// Stop the voice and drop everything queued on it
+    fn clear_queue(&mut self, voice: VoiceId);
+}
+
+pub type SharedBackend = Rc<RefCell<Box<AudioBackend>>>;
+
+///
+/// Use OpenAL unless $SRE_AUDIO=silent, falling back to silence
+/// if no device can be opened so the game still runs on headless machines
+///
+pub fn open_backend() -> SharedBackend {
+
+    let silent = env::var(AUDIO_BACKEND_VAR).map(|value| value == "silent").unwrap_or(false);
+
+    let backend: Box<AudioBackend> = if silent {
+        Box::new(SilentBackend::new())
+    } else {
+        match OpenAlBackend::new() {
+            Ok(backend) => Box::new(backend),
+            Err(e) => {
+                println!("Warning: {}, continuing without sound", e);
+                Box::new(SilentBackend::new())
+            }
+        }
+    };
+
+    Rc::new(RefCell::new(backend))
+}
+
+pub struct OpenAlBackend {
+    device: alc::Device,
+    context: alc::Context,
+    buffers: Vec<al::Buffer>,
+    sources: Vec<al::Source>,
+    /// Sound last played on each source, None for streaming sources
+    attached: Vec<Option<SoundId>>,
+    /// Buffers queued on each streaming voice, oldest first, kept alive until unqueued
+    stream_queues: HashMap<usize, VecDeque<al::Buffer>>,
+    /// Streaming buffers that have been played and can be refilled
+    free_stream_buffers: Vec<al::Buffer>,
+}
+
+impl OpenAlBackend {
+    pub fn new() -> Result<OpenAlBackend> {
+
+        let device = try!(alc::Device::open(None).ok_or(Error::AudioDevice("could not open device".to_string())));
+        let context = try!(device.create_context(&[]).ok_or(Error::AudioDevice("could not create context".to_string())));
+        context.make_current();
+
+        Ok(OpenAlBackend {
+            device: device,
+            context: context,
+            buffers: Vec::new(),
+            sources: Vec::new(),
+            attached: Vec::new(),
+            stream_queues: HashMap::new(),
+            free_stream_buffers: Vec::new(),
+        })
+    }
+}
+
+fn buffer_data(buffer: &mut al::Buffer, samples: &[i16], channels: u32, sample_rate: u32) {
+    let format = if channels == 1 { al::Format::Mono16 } else { al::Format::Stereo16 };
+    unsafe { buffer.buffer_data(format, samples, sample_rate as al::ALsizei) };
+}
+
+impl AudioBackend for OpenAlBackend {
+
+    fn create_sound(&mut self, name: &str, data: &SoundData) -> SoundId {
+        let mut buffer = al::Buffer::gen();
+        buffer_data(&mut buffer, &data.samples, data.channels, data.sample_rate);
+        self.buffers.push(buffer);
+        SoundId(self.buffers.len() - 1)
+    }
+
+    fn update_sound(&mut self, sound: SoundId, data: &SoundData) {
+        // OpenAL won't replace the data of a buffer that's queued on a source,
+        // only sources playing this sound need interrupting
+        for (source, attached) in self.sources.iter_mut().zip(self.attached.iter()) {
+            if *attached == Some(sound) {
+                source.stop();
+            }
+        }
+        buffer_data(&mut self.buffers[sound.0], &data.samples, data.channels, data.sample_rate);
+    }
+
+    fn create_voice(&mut self) -> VoiceId {
+        // Positioned relative to the listener, panning is done by placing the source
+        // on a unit circle in front of it, distance attenuation is left to the mixer
+        let mut source = al::Source::gen();
+        source.set_relative(true);
+        source.set_position([0.0, 0.0, -1.0]);
+        self.sources.push(source);
+        self.attached.push(None);
+        VoiceId(self.sources.len() - 1)
+    }
+
+    fn play(&mut self, voice: VoiceId, sound: SoundId) {
+        let source = &mut self.sources[voice.0];
+        source.stop();
+        source.set_buffer(self.buffers[sound.0].clone());
+        source.play();
+        self.attached[voice.0] = Some(sound);
+    }
+
+    fn stop(&mut self, voice: VoiceId) {
+        self.sources[voice.0].stop();
+    }
+
+    fn stop_all(&mut self) {
+        for source in self.sources.iter_mut() {
+            source.stop();
+        }
+    }
+
+    fn pause(&mut self, voice: VoiceId) {
+        let source = &mut self.sources[voice.0];
+        if source.get_state() == al::State::Playing {
+            source.pause();
+        }
+    }
+
+    fn resume(&mut self, voice: VoiceId) {
+        let source = &mut self.sources[voice.0];
+        if source.get_state() == al::State::Paused {
+            source.play();
         }
     }
 
-    pub fn load_file(&mut self, path: &str) {
-        load_buffer(path, &mut self.buffer);
+    fn is_playing(&self, voice: VoiceId) -> bool {
+        self.sources[voice.0].get_state() == al::State::Playing
     }
 
-    pub fn play(&mut self) {
-        self.source.set_buffer(self.buffer.clone());
-        self.source.play();
+    fn set_gain(&mut self, voice: VoiceId, gain: f32) {
+        self.sources[voice.0].set_gain(gain);
     }
 
-    pub fn play_buffer(&mut self, buffer: al::Buffer) {
-        self.source.stop();
-        self.source.set_buffer(buffer.clone());
-        self.source.play();
+    fn set_pan(&mut self, voice: VoiceId, pan: f32) {
+        self.sources[voice.0].set_position([pan, 0.0, -(1.0 - pan * pan).sqrt()]);
+    }
+
+    fn set_looping(&mut self, voice: VoiceId, looping: bool) {
+        self.sources[voice.0].set_looping(looping);
+    }
+
+    fn queue_samples(&mut self, voice: VoiceId, samples: &[i16], channels: u32, sample_rate: u32) {
+        let mut buffer = match self.free_stream_buffers.pop() {
+            Some(buffer) => buffer,
+            None => al::Buffer::gen(),
+        };
+        buffer_data(&mut buffer, samples, channels, sample_rate);
+        self.sources[voice.0].queue_buffer(&buffer);
+        self.attached[voice.0] = None;
+        self.stream_queues.entry(voice.0).or_insert(VecDeque::new()).push_back(buffer);
+    }
+
+    fn unqueue_processed(&mut self, voice: VoiceId) -> usize {
+        let source = &mut self.sources[voice.0];
+        let queue = self.stream_queues.entry(voice.0).or_insert(VecDeque::new());
+
+        for _ in 0..source.get_buffers_processed() {
+            if let Some(buffer) = queue.pop_front() {
+                source.unqueue_buffer(&buffer);
+                self.free_stream_buffers.push(buffer);
+            }
+        }
+
+        queue.len()
+    }
+
+    fn play_queued(&mut self, voice: VoiceId) {
+        let source = &mut self.sources[voice.0];
+        if source.get_state() != al::State::Playing {
+            source.play();
+        }
+    }
+
+    fn clear_queue(&mut self, voice: VoiceId) {
+        let source = &mut self.sources[voice.0];
+        source.stop();
+
+        // Every buffer counts as processed once the source is stopped
+        if let Some(queue) = self.stream_queues.get_mut(&voice.0) {
+            while let Some(buffer) = queue.pop_front() {
+                source.unqueue_buffer(&buffer);
+                self.free_stream_buffers.push(buffer);
+            }
+        }
     }
 }
 
-// TODO - figure out how to get the AudioSystem to own device/context and cleanup properly..
-// use Drop trait?
+impl Drop for OpenAlBackend {
+    fn drop(&mut self) {
+        self.sources.clear();
+        self.stream_queues.clear();
+        self.free_stream_buffers.clear();
+        self.buffers.clear();
+        self.device.close().ok().expect("Unable to close device");
+    }
+}
 
-pub struct AudioSystem
-{
-    device: alc::Device,
-    context: alc::Context,
+///
+/// Plays nothing. When recording, the name of every sound played is
+/// appended to a shared log, so tests can check which sounds were triggered.
+///
+pub struct SilentBackend {
+    sounds: Vec<String>,
+    /// Buffers queued on each voice, never processed so streams fill up once and idle
+    queued: Vec<usize>,
+    log: Option<Rc<RefCell<Vec<String>>>>,
 }
 
-impl AudioSystem {
-    pub fn new() -> AudioSystem {
+impl SilentBackend {
 
-        let device = alc::Device::open(None).expect("Could not open device");
-        let context = device.create_context(&[]).expect("Could not create context");
-        context.make_current();
+    pub fn new() -> SilentBackend {
+        SilentBackend {
+            sounds: Vec::new(),
+            queued: Vec::new(),
+            log: None,
+        }
+    }
 
+    pub fn recording(log: Rc<RefCell<Vec<String>>>) -> SilentBackend {
+        SilentBackend {
+            sounds: Vec::new(),
+            queued: Vec::new(),
+            log: Some(log),
+        }
+    }
+}
+
+impl AudioBackend for SilentBackend {
+
+    fn create_sound(&mut self, name: &str, data: &SoundData) -> SoundId {
+        self.sounds.push(name.to_string());
+        SoundId(self.sounds.len() - 1)
+    }
+
+    fn update_sound(&mut self, sound: SoundId, data: &SoundData) {}
+
+    fn create_voice(&mut self) -> VoiceId {
+        self.queued.push(0);
+        VoiceId(self.queued.len() - 1)
+    }
+
+    fn play(&mut self, voice: VoiceId, sound: SoundId) {
+        if let Some(ref log) = self.log {
+            log.borrow_mut().push(self.sounds[sound.0].clone());
+        }
+    }
+
+    fn stop(&mut self, voice: VoiceId) {}
+
+    fn stop_all(&mut self) {}
+
+    fn pause(&mut self, voice: VoiceId) {}
+
+    fn resume(&mut self, voice: VoiceId) {}
+
+    /// Sounds finish instantly, so voices are never stolen
+    fn is_playing(&self, voice: VoiceId) -> bool {
+        false
+    }
+
+    fn set_gain(&mut self, voice: VoiceId, gain: f32) {}
+
+    fn set_pan(&mut self, voice: VoiceId, pan: f32) {}
+
+    fn set_looping(&mut self, voice: VoiceId, looping: bool) {}
+
+    fn queue_samples(&mut self, voice: VoiceId, samples: &[i16], channels: u32, sample_rate: u32) {
+        self.queued[voice.0] += 1;
+    }
+
+    fn unqueue_processed(&mut self, voice: VoiceId) -> usize {
+        self.queued[voice.0]
+    }
+
+    fn play_queued(&mut self, voice: VoiceId) {}
+
+    fn clear_queue(&mut self, voice: VoiceId) {
+        self.queued[voice.0] = 0;
+    }
+}
+
+impl world::AudioSource {
+
+    pub fn new() -> world::AudioSource {
+        world::AudioSource {
+            pending: Vec::new(),
+        }
+    }
+
+    ///
+    /// Play a sound effect from this source at normal priority.
+    /// Picked up by the AudioSystem on its next update.
+    ///
+    pub fn play(&mut self, sound: SoundId) {
+        self.play_with(sound, SoundParams::sfx(DEFAULT_PRIORITY));
+    }
+
+    pub fn play_with(&mut self, sound: SoundId, params: SoundParams) {
+        self.pending.push((sound, params));
+    }
+}
+
+/// Priority of sounds played without explicit params
+pub const DEFAULT_PRIORITY: u8 = 128;
+
+///
+/// Plays sounds requested through AudioSources and `Event::PlaySound`, which
+/// can be posted to any one receiver, and applies `Event::SetVolume`.
+///
+pub struct AudioSystem {
+    backend: SharedBackend,
+    mixer: Mixer,
+}
+
+impl AudioSystem {
+    pub fn new(backend: SharedBackend) -> AudioSystem {
         AudioSystem {
-            device: device,
-            context: context,
+            backend: backend,
+            mixer: Mixer::new(),
         }
     }
 }
 
 impl world::System for AudioSystem {
-    fn update(&mut self, control_state: &world::ControlState, components: &mut world::Components, entities: &mut Vec<world::Entity>) { }
+
+    fn update(&mut self, control_state: &world::ControlState, components: &mut world::Components, entities: &mut Vec<world::Entity>) {
+
+        let mut backend = self.backend.borrow_mut();
+
+        // Listen from whatever the camera follows
+        for entity in entities.iter() {
+            if let (Some(_), Some(position_id)) = (entity.camera_target, entity.position) {
+                let position = components.position.get(position_id);
+                self.mixer.listener = [position.x, position.y];
+                break;
+            }
+        }
+
+        for entity in entities.iter() {
+
+            // Sounds come from the entity that played them, if it has a position
+            let position = entity.position.map(|position_id| {
+                let position = components.position.get(position_id);
+                [position.x, position.y]
+            });
+
+            if let Some(receiver_id) = entity.event_receiver {
+                for event in components.event_receiver.get(receiver_id).event_queue.iter() {
+                    match *event {
+                        world::Event::PlaySound(sound, mut params) => {
+                            if params.position.is_none() {
+                                params.position = position;
+                            }
+                            self.mixer.play(&mut **backend, sound, params, None);
+                        },
+                        world::Event::SetVolume(group, volume) => {
+                            self.mixer.set_volume(&mut **backend, group, volume);
+                        },
+                        _ => {},
+                    }
+                }
+            }
+
+            if let Some(source_id) = entity.audio_source {
+                let audio_source = components.audio_source.get_mut(source_id);
+
+                for (sound, mut params) in audio_source.pending.drain() {
+                    if params.position.is_none() {
+                        params.position = position;
+                    }
+                    self.mixer.play(&mut **backend, sound, params, Some(source_id));
+                }
+
+                if let Some(position) = position {
+                    self.mixer.move_owner(source_id, position);
+                }
+            }
+        }
+
+        self.mixer.update_spatial(&mut **backend);
+    }
+
     fn render(&mut self, context: &Context, gl: &mut GlGraphics, components: &mut world::Components, entities: &mut Vec<world::Entity>) { }
-}
 
-impl Drop for AudioSystem {
-    fn drop(&mut self) {
-        self.device.close().ok().expect("Unable to close device");
+    fn set_paused(&mut self, paused: bool, components: &mut world::Components, entities: &mut Vec<world::Entity>) {
+
+        self.mixer.set_paused(&mut **self.backend.borrow_mut(), paused);
     }
 }
 
-pub fn load_buffer(path: &str, buffer: &mut al::Buffer) -> f32 {
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
 
-  let mut sound_file = SndFile::new(path, OpenMode::Read).unwrap();
+    use event::EventSystem;
+    use mixer::{SoundParams, VolumeGroup};
+    use pcm::SoundData;
+    use world::{self, System};
+    use super::{AudioBackend, AudioSystem, SharedBackend, SilentBackend, SoundId, DEFAULT_PRIORITY};
 
-  let sndinfo = sound_file.get_sndinfo();
-  let duration = sndinfo.frames as f32 / sndinfo.samplerate as f32;
+    fn recording_backend(sounds: &[&str]) -> (SharedBackend, Vec<SoundId>, Rc<RefCell<Vec<String>>>) {
 
-  let mut samples: Vec<i16> = Vec::new();
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let mut backend: Box<AudioBackend> = Box::new(SilentBackend::recording(log.clone()));
 
-  let mut read_buf: [i16; READ_BUFFER_SIZE] = [0; READ_BUFFER_SIZE];
-  let mut samples_read = 0;
+        let data = SoundData { samples: vec![0; 64], channels: 1, sample_rate: 44100 };
+        let ids = sounds.iter().map(|name| backend.create_sound(name, &data)).collect();
 
-  loop {
-      let samples_read = sound_file.read_i16(&mut read_buf[..], READ_BUFFER_SIZE as i64);
-      if samples_read == 0 { break; }
-      samples.push_all(read_buf.slice(0, samples_read as usize));
-  }
+        (Rc::new(RefCell::new(backend)), ids, log)
+    }
+
+    #[test]
+    fn plays_sounds_posted_as_events_once() {
+
+        let (backend, sounds, log) = recording_backend(&["Jump.wav", "Land.wav"]);
 
-  let format = if sndinfo.channels == 1 { al::Format::Mono16 } else { al::Format::Stereo16 };
+        let mut world = world::World::new();
+        let mut listener = world::empty_entity();
+        let receiver_id = world.data.event_receiver.add(world::EventReceiver::new());
+        listener.event_receiver = Some(receiver_id);
+        world.entities.push(listener);
 
-  unsafe { buffer.buffer_data(format, &samples, sndinfo.samplerate as al::ALsizei) };
+        let control_state = world::ControlState::new();
+        let mut audio = AudioSystem::new(backend);
+        let mut events = EventSystem;
+
+        {
+            let receiver = world.data.event_receiver.get_mut(receiver_id);
+            receiver.post_event(world::Event::PlaySound(sounds[1], SoundParams::sfx(DEFAULT_PRIORITY)));
+            receiver.post_event(world::Event::SetVolume(VolumeGroup::Sfx, 0.5));
+        }
 
-  duration
+        // Not readable until the events are swapped in at the end of the update
+        audio.update(&control_state, &mut world.data, &mut world.entities);
+        assert!(log.borrow().is_empty());
 
+        events.update(&control_state, &mut world.data, &mut world.entities);
+        audio.update(&control_state, &mut world.data, &mut world.entities);
+        assert_eq!(*log.borrow(), vec!["Land.wav".to_string()]);
+
+        events.update(&control_state, &mut world.data, &mut world.entities);
+        audio.update(&control_state, &mut world.data, &mut world.entities);
+        assert_eq!(log.borrow().len(), 1);
+    }
+
+    #[test]
+    fn plays_sounds_from_audio_sources() {
+
+        let (backend, sounds, log) = recording_backend(&["Jump.wav", "Land.wav"]);
+
+        let mut world = world::World::new();
+        let mut jumper = world::empty_entity();
+        let source_id = world.data.audio_source.add(world::AudioSource::new());
+        jumper.position = Some(world.data.position.add(world::Position { x: 0.0, y: 0.0 }));
+        jumper.audio_source = Some(source_id);
+        world.entities.push(jumper);
+
+        let control_state = world::ControlState::new();
+        let mut audio = AudioSystem::new(backend);
+
+        world.data.audio_source.get_mut(source_id).play(sounds[0]);
+        world.data.audio_source.get_mut(source_id).play(sounds[1]);
+        audio.update(&control_state, &mut world.data, &mut world.entities);
+        audio.update(&control_state, &mut world.data, &mut world.entities);
+
+        assert_eq!(*log.borrow(), vec!["Jump.wav".to_string(), "Land.wav".to_string()]);
+        assert!(world.data.audio_source.get(source_id).pending.is_empty());
+    }
 }
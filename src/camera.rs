@@ -0,0 +1,346 @@
+use graphics::Context;
+use opengl_graphics::GlGraphics;
+use vecmath::*;
+use std::num::Float;
+
+use world;
+use time;
+use units;
+
+impl world::Camera {
+
+    pub fn new(x: f32, y: f32) -> world::Camera {
+        world::Camera {
+            position: [x, y],
+            viewport: [units::VIRTUAL_WIDTH as f32, units::VIRTUAL_HEIGHT as f32],
+            smoothing: 8.0,
+            dead_zone: [units::tiles(2.0), units::tiles(1.5)],
+            look_ahead: units::tiles(1.5),
+            bounds: None,
+            zoom: 1.0,
+            target_zoom: 1.0,
+            zoom_smoothing: 4.0,
+            shake_trauma: 0.0,
+            shake_decay: 1.5,
+            shake_magnitude: units::tiles(0.375),
+            shake_time: 0.0,
+            shake_offset: [0.0, 0.0],
+        }
+    }
+
+    ///
+    /// Add a screen shake impulse, trauma is capped at 1.0
+    ///
+    pub fn shake(&mut self, amount: f32) {
+        self.shake_trauma = (self.shake_trauma + amount).min(1.0);
+    }
+
+    ///
+    /// Move the camera towards `target`, offset by `aim` * `look_ahead`.
+    /// The camera only moves once the focus point leaves the dead zone.
+    ///
+    pub fn follow(&mut self, target: Vector2<f32>, aim: Vector2<f32>, dt: f32) {
+
+        let focus = vec2_add(target, vec2_scale(aim, self.look_ahead));
+        let goal = dead_zone_goal(self.position, focus, self.dead_zone);
+
+        let t = smoothing_factor(self.smoothing, dt);
+        self.position = vec2_add(self.position, vec2_scale(vec2_sub(goal, self.position), t));
+
+        let zoom_t = smoothing_factor(self.zoom_smoothing, dt);
+        self.zoom += (self.target_zoom - self.zoom) * zoom_t;
+
+        self.update_shake(dt);
+        self.clamp_to_bounds();
+    }
+
+    fn update_shake(&mut self, dt: f32) {
+        self.shake_trauma = (self.shake_trauma - self.shake_decay * dt).max(0.0);
+        self.shake_time += dt;
+        self.shake_offset = shake_offset(self.shake_trauma, self.shake_magnitude, self.shake_time);
+    }
+
+    ///
+    /// Pixels of the virtual screen per world unit, including zoom
+    ///
+    pub fn scale(&self) -> f32 {
+        units::pixels_per_unit() * self.zoom
+    }
+
+    ///
+    /// Size of the visible area in world units
+    ///
+    pub fn view_size(&self) -> Vector2<f32> {
+        [self.viewport[0] / self.scale(), self.viewport[1] / self.scale()]
+    }
+
+    pub fn clamp_to_bounds(&mut self) {
+        if let Some(bounds) = self.bounds {
+            self.position = clamp_to_bounds(self.position, self.view_size(), bounds);
+        }
+    }
+
+    ///
+    /// Camera center including any shake offset, still kept inside the bounds
+    ///
+    pub fn view_center(&self) -> Vector2<f32> {
+        let center = vec2_add(self.position, self.shake_offset);
+        match self.bounds {
+            Some(bounds) => clamp_to_bounds(center, self.view_size(), bounds),
+            None => center,
+        }
+    }
+
+    pub fn get_context(&self) -> Context {
+        let mat = view_matrix(self.view_center(), self.viewport, self.scale());
+        let mut context = Context::new();
+        context.view = mat;
+        context.transform = mat;
+        context
+    }
+}
+
+///
+/// Exponential smoothing factor for a given rate, independent of frame rate.
+/// A rate of zero (or less) snaps immediately.
+///
+pub fn smoothing_factor(rate: f32, dt: f32) -> f32 {
+    if rate > 0.0 {
+        1.0 - (-rate * dt).exp()
+    } else {
+        1.0
+    }
+}
+
+///
+/// Returns the camera position needed to bring `focus` back inside the dead zone
+/// centered on `position`. `dead_zone` is the full width and height of the zone.
+///
+pub fn dead_zone_goal(position: Vector2<f32>, focus: Vector2<f32>, dead_zone: [f32; 2]) -> Vector2<f32> {
+    let mut goal = position;
+
+    for axis in 0..2 {
+        let half = dead_zone[axis] / 2.0;
+        let delta = focus[axis] - position[axis];
+        if delta > half {
+            goal[axis] = focus[axis] - half;
+        } else if delta < -half {
+            goal[axis] = focus[axis] + half;
+        }
+    }
+
+    goal
+}
+
+///
+/// Keep a view of `view_size` centered on `position` inside `bounds` (left, top, right, bottom).
+/// If the view is larger than the bounds on an axis, the view is centered on the bounds instead.
+///
+pub fn clamp_to_bounds(position: Vector2<f32>, view_size: Vector2<f32>, bounds: [f32; 4]) -> Vector2<f32> {
+    let mut clamped = position;
+
+    for axis in 0..2 {
+        let min = bounds[axis];
+        let max = bounds[axis + 2];
+        let half = view_size[axis] / 2.0;
+
+        if max - min <= view_size[axis] {
+            clamped[axis] = (min + max) / 2.0;
+        } else {
+            clamped[axis] = position[axis].max(min + half).min(max - half);
+        }
+    }
+
+    clamped
+}
+
+///
+/// Cheap deterministic shake, scaled by trauma squared so small impulses stay subtle
+///
+pub fn shake_offset(trauma: f32, magnitude: f32, time: f32) -> Vector2<f32> {
+    let amount = trauma * trauma * magnitude;
+    [
+        amount * (time * 47.0).sin(),
+        amount * (time * 59.0 + 1.3).sin(),
+    ]
+}
+
+///
+/// World to normalized device coordinates, `viewport` is in virtual pixels
+/// and `scale` is virtual pixels per world unit.
+///
+/// Y axis needs to be down, otherwise `sprite` gets messed...
+///
+pub fn view_matrix(center: Vector2<f32>, viewport: Vector2<f32>, scale: f32) -> [[f64; 3]; 2] {
+    let sx = 2.0 * scale as f64 / viewport[0] as f64;
+    let sy = 2.0 * scale as f64 / viewport[1] as f64;
+    let tx = -center[0] as f64 * sx;
+    let ty = center[1] as f64 * sy;
+
+    [[ sx,  0.0, tx],
+     [ 0.0, -sy, ty]]
+}
+
+pub struct CameraSystem {
+    last_update: f64,
+}
+
+impl CameraSystem {
+    pub fn new() -> CameraSystem {
+        CameraSystem {
+            last_update: time::precise_time_s(),
+        }
+    }
+}
+
+impl world::System for CameraSystem {
+
+    fn update(&mut self, control_state: &world::ControlState, components: &mut world::Components, entities: &mut Vec<world::Entity>) {
+
+        let now = time::precise_time_s();
+        let dt = (now - self.last_update) as f32;
+        self.last_update = now;
+
+        // Follow the first entity with a CameraTarget, looking ahead along its aim if it has one
+        let mut target = None;
+        for entity in entities.iter() {
+            if let (Some(position_id), Some(_)) = (entity.position, entity.camera_target) {
+                let position = components.position.get(position_id);
+                let aim = match entity.player_controller {
+                    Some(player_id) => components.player_controller.get(player_id).aim_direction,
+                    None => [0.0, 0.0],
+                };
+                target = Some(([position.x, position.y], aim));
+                break;
+            }
+        }
+
+        for entity in entities.iter() {
+            if let Some(camera_id) = entity.camera {
+                let camera = components.camera.get_mut(camera_id);
+
+                if let Some(receiver_id) = entity.event_receiver {
+                    let receiver = components.event_receiver.get(receiver_id);
+                    for event in receiver.event_queue.iter() {
+                        match *event {
+                            world::Event::CameraShake(amount) => camera.shake(amount),
+                            world::Event::CameraZoom(zoom) => camera.target_zoom = zoom,
+                            _ => {},
+                        }
+                    }
+                }
+
+                if let Some((position, aim)) = target {
+                    camera.follow(position, aim, dt);
+                }
+            }
+        }
+    }
+
+    fn set_paused(&mut self, paused: bool, components: &mut world::Components, entities: &mut Vec<world::Entity>) {
+        if !paused {
+            self.last_update = time::precise_time_s();
+        }
+    }
+
+    fn render(&mut self, context: &Context, gl: &mut GlGraphics, components: &mut world::Components, entities: &mut Vec<world::Entity>) {
+        // not implemented
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use vecmath::*;
+    use std::num::Float;
+
+    use world;
+    use super::{smoothing_factor, dead_zone_goal, clamp_to_bounds, shake_offset, view_matrix};
+
+    fn assert_close(actual: Vector2<f32>, expected: Vector2<f32>) {
+        assert!((actual[0] - expected[0]).abs() < 1e-4 && (actual[1] - expected[1]).abs() < 1e-4,
+            "expected {:?}, got {:?}", expected, actual);
+    }
+
+    #[test]
+    fn smoothing_snaps_without_a_rate() {
+        assert_eq!(smoothing_factor(0.0, 0.016), 1.0);
+        assert_eq!(smoothing_factor(-1.0, 0.016), 1.0);
+        assert_eq!(smoothing_factor(8.0, 0.0), 0.0);
+    }
+
+    #[test]
+    fn smoothing_is_frame_rate_independent() {
+        // Two short frames cover the same ground as one long one
+        let short = smoothing_factor(8.0, 0.01);
+        let long = smoothing_factor(8.0, 0.02);
+        let remaining = (1.0 - short) * (1.0 - short);
+        assert!((1.0 - remaining - long).abs() < 1e-5);
+        assert!(long > 0.0 && long < 1.0);
+    }
+
+    #[test]
+    fn dead_zone_holds_still_inside() {
+        assert_close(dead_zone_goal([0.0, 0.0], [0.9, -0.4], [2.0, 1.0]), [0.0, 0.0]);
+    }
+
+    #[test]
+    fn dead_zone_moves_just_enough() {
+        assert_close(dead_zone_goal([0.0, 0.0], [3.0, 0.0], [2.0, 1.0]), [2.0, 0.0]);
+        assert_close(dead_zone_goal([0.0, 0.0], [-3.0, 2.0], [2.0, 1.0]), [-2.0, 1.5]);
+    }
+
+    #[test]
+    fn clamp_keeps_view_inside_bounds() {
+        let bounds = [0.0, 0.0, 20.0, 10.0];
+        assert_close(clamp_to_bounds([10.0, 5.0], [4.0, 2.0], bounds), [10.0, 5.0]);
+        assert_close(clamp_to_bounds([-5.0, 0.5], [4.0, 2.0], bounds), [2.0, 1.0]);
+        assert_close(clamp_to_bounds([25.0, 12.0], [4.0, 2.0], bounds), [18.0, 9.0]);
+    }
+
+    #[test]
+    fn clamp_centers_views_larger_than_bounds() {
+        assert_close(clamp_to_bounds([3.0, 3.0], [30.0, 2.0], [0.0, 0.0, 20.0, 10.0]), [10.0, 3.0]);
+    }
+
+    #[test]
+    fn shake_scales_with_trauma_squared() {
+        assert_close(shake_offset(0.0, 1.0, 0.3), [0.0, 0.0]);
+
+        for i in 0..100 {
+            let time = i as f32 * 0.037;
+            let full = shake_offset(1.0, 2.0, time);
+            let half = shake_offset(0.5, 2.0, time);
+            assert!(full[0].abs() <= 2.0 && full[1].abs() <= 2.0);
+            assert_close(half, vec2_scale(full, 0.25));
+        }
+    }
+
+    #[test]
+    fn shake_stays_inside_bounds() {
+        let mut camera = world::Camera::new(0.0, 0.0);
+        let view = camera.view_size();
+        camera.bounds = Some([0.0, 0.0, view[0] * 2.0, view[1] * 2.0]);
+        camera.clamp_to_bounds();
+        camera.shake_offset = [-camera.shake_magnitude, -camera.shake_magnitude];
+
+        let center = camera.view_center();
+        assert!(center[0] - view[0] / 2.0 >= -1e-4);
+        assert!(center[1] - view[1] / 2.0 >= -1e-4);
+    }
+
+    #[test]
+    fn view_matrix_maps_viewport_to_device_coordinates() {
+        let matrix = view_matrix([10.0, 5.0], [320.0, 240.0], 32.0);
+        let transform = |x: f64, y: f64| [
+            matrix[0][0] * x + matrix[0][1] * y + matrix[0][2],
+            matrix[1][0] * x + matrix[1][1] * y + matrix[1][2],
+        ];
+
+        let center = transform(10.0, 5.0);
+        assert!(center[0].abs() < 1e-9 && center[1].abs() < 1e-9);
+
+        // Half a viewport right and down is the bottom right corner, y is flipped
+        let corner = transform(10.0 + 5.0, 5.0 + 3.75);
+        assert!((corner[0] - 1.0).abs() < 1e-9 && (corner[1] + 1.0).abs() < 1e-9);
+    }
+}
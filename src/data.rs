@@ -0,0 +1,98 @@
+///
+/// Minimal sectioned key/value format for game data:
+///
+/// ```text
+/// # comment
+/// [section name]
+/// key = value
+/// ```
+///
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::str::FromStr;
+
+use error::{self, Error};
+
+pub struct Section {
+    pub name: String,
+    pub values: Vec<(String, String)>,
+}
+
+impl Section {
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.values.iter()
+            .find(|&&(ref k, _)| &k[..] == key)
+            .map(|&(_, ref v)| &v[..])
+    }
+
+    pub fn get_or<T: FromStr>(&self, key: &str, default: T) -> Result<T, String> {
+        match self.get(key) {
+            Some(value) => value.parse().map_err(|_| {
+                format!("[{}] invalid value for {}: '{}'", self.name, key, value)
+            }),
+            None => Ok(default),
+        }
+    }
+
+    pub fn require<T: FromStr>(&self, key: &str) -> Result<T, String> {
+        match self.get(key) {
+            Some(value) => value.parse().map_err(|_| {
+                format!("[{}] invalid value for {}: '{}'", self.name, key, value)
+            }),
+            None => Err(format!("[{}] missing {}", self.name, key)),
+        }
+    }
+}
+
+pub fn parse(text: &str) -> Result<Vec<Section>, String> {
+
+    let mut sections: Vec<Section> = Vec::new();
+
+    for (line_number, line) in text.lines().enumerate() {
+        let line = line.trim();
+
+        if line.len() == 0 || line.starts_with("#") {
+            continue;
+        }
+
+        if line.starts_with("[") && line.ends_with("]") {
+            sections.push(Section {
+                name: line[1..line.len() - 1].trim().to_string(),
+                values: Vec::new(),
+            });
+            continue;
+        }
+
+        let mut parts = line.splitn(2, '=');
+        let key = parts.next().unwrap().trim();
+
+        let value = match parts.next() {
+            Some(value) => value.trim(),
+            None => return Err(format!("line {}: expected 'key = value'", line_number + 1)),
+        };
+
+        match sections.last_mut() {
+            Some(section) => section.values.push((key.to_string(), value.to_string())),
+            None => return Err(format!("line {}: '{}' is outside of any [section]", line_number + 1, key)),
+        }
+    }
+
+    Ok(sections)
+}
+
+pub fn load(path: &Path) -> error::Result<Vec<Section>> {
+
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) => return Err(Error::Io(path.display().to_string(), e)),
+    };
+
+    let mut text = String::new();
+    if let Err(e) = file.read_to_string(&mut text) {
+        return Err(Error::Io(path.display().to_string(), e));
+    }
+
+    parse(&text[..]).map_err(|e| Error::Data(path.display().to_string(), e))
+}
@@ -0,0 +1,51 @@
+use std::error;
+use std::fmt;
+use std::io;
+use std::result;
+
+///
+/// Anything that can go wrong while loading the game, naming the asset involved
+///
+#[derive(Debug)]
+pub enum Error {
+    /// File couldn't be opened or read
+    Io(String, io::Error),
+    /// Malformed Tiled map
+    Map(String, String),
+    /// Texture couldn't be decoded or uploaded
+    Image(String, String),
+    /// Sound file couldn't be decoded
+    Sound(String, String),
+    /// Bad value in a data file, e.g. weapons.cfg
+    Data(String, String),
+    /// No usable audio device or context
+    AudioDevice(String),
+}
+
+pub type Result<T> = result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Io(ref asset, ref e) => write!(f, "{}: {}", asset, e),
+            Error::Map(ref asset, ref e) => write!(f, "{}: invalid map: {}", asset, e),
+            Error::Image(ref asset, ref e) => write!(f, "{}: couldn't load image: {}", asset, e),
+            Error::Sound(ref asset, ref e) => write!(f, "{}: couldn't load sound: {}", asset, e),
+            Error::Data(ref asset, ref e) => write!(f, "{}: {}", asset, e),
+            Error::AudioDevice(ref e) => write!(f, "audio device: {}", e),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn description(&self) -> &str {
+        match *self {
+            Error::Io(..) => "I/O error",
+            Error::Map(..) => "invalid map",
+            Error::Image(..) => "invalid image",
+            Error::Sound(..) => "invalid sound",
+            Error::Data(..) => "invalid data",
+            Error::AudioDevice(..) => "audio device error",
+        }
+    }
+}
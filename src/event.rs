@@ -1,6 +1,8 @@
 use graphics::Context;
 use opengl_graphics::GlGraphics;
 
+use std::mem;
+
 use world;
 
 impl world::EventReceiver {
@@ -8,6 +10,7 @@ impl world::EventReceiver {
     pub fn new() -> world::EventReceiver {
         world::EventReceiver {
             event_queue: Vec::new(),
+            pending_events: Vec::new(),
         }
     }
 
@@ -17,28 +20,45 @@ impl world::EventReceiver {
         // iterate over components, look for ones that implement handle_event?
 
         // Send events to systems??
-        self.event_queue.push(event);
+        self.pending_events.push(event);
     }
 
-    pub fn clear_events(&mut self) {
+    ///
+    /// Make this frame's events readable and drop last frame's.
+    /// Swapping keeps both queues' allocations around.
+    ///
+    pub fn swap_events(&mut self) {
         self.event_queue.clear();
+        mem::swap(&mut self.event_queue, &mut self.pending_events);
     }
 
 }
 
+///
+/// Post an event to every entity with an EventReceiver
+///
+pub fn broadcast_event(event: world::Event, components: &mut world::Components, entities: &Vec<world::Entity>) {
+    for entity in entities.iter() {
+        if let Some(receiver_id) = entity.event_receiver {
+            components.event_receiver.get_mut(receiver_id).post_event(event);
+        }
+    }
+}
+
 pub struct EventSystem;
 
 impl world::System for EventSystem {
 
     ///
-    /// Should be handled after all other systems...?
+    /// Should be handled after all other systems, so events posted at any point
+    /// in a frame are seen by every system during the next one
     ///
     fn update(&mut self, control_state: &world::ControlState, components: &mut world::Components, entities: &mut Vec<world::Entity>) {
 
         for entity in entities.iter() {
             if let Some(receiver_id) = entity.event_receiver {
                 let receiver = components.event_receiver.get_mut(receiver_id);
-                receiver.clear_events();
+                receiver.swap_events();
             }
         }
 
@@ -0,0 +1,272 @@
+use graphics::Context;
+use opengl_graphics::GlGraphics;
+use vecmath::*;
+use std::num::Float;
+use std::cmp;
+
+use world;
+use time;
+
+/// Score for killing a roach
+const ENEMY_POINTS: u32 = 100;
+/// Score for destroying a hive
+const HIVE_POINTS: u32 = 1000;
+
+impl world::Health {
+
+    pub fn new(max: f32, respawn_point: Vector2<f32>) -> world::Health {
+        world::Health {
+            current: max,
+            max: max,
+            lives: None,
+
+            invulnerability: 1.0,
+            invulnerable_until: 0.0,
+            stun: 0.2,
+            stunned_until: 0.0,
+            flash_interval: 0.08,
+
+            dead: false,
+            death_time: 0.0,
+            respawn_delay: 1.5,
+            respawn_point: respawn_point,
+
+            hurt_sound: None,
+            death_sound: None,
+            death_anim: None,
+        }
+    }
+
+    pub fn is_invulnerable(&self, time: f64) -> bool {
+        time < self.invulnerable_until
+    }
+
+    ///
+    /// Stunned entities shouldn't steer themselves, so knockback can play out
+    ///
+    pub fn is_stunned(&self, time: f64) -> bool {
+        self.dead || time < self.stunned_until
+    }
+
+    ///
+    /// Returns true if the damage was taken
+    ///
+    pub fn apply_damage(&mut self, amount: f32, time: f64) -> bool {
+
+        if self.dead || self.is_invulnerable(time) {
+            return false;
+        }
+
+        self.current = (self.current - amount).max(0.0);
+        self.invulnerable_until = time + self.invulnerability as f64;
+        self.stunned_until = time + self.stun as f64;
+
+        if self.current <= 0.0 {
+            self.dead = true;
+            self.death_time = time;
+        }
+
+        true
+    }
+
+    pub fn revive(&mut self, time: f64) {
+        self.current = self.max;
+        self.dead = false;
+        self.invulnerable_until = time + self.invulnerability as f64;
+        self.stunned_until = 0.0;
+    }
+}
+
+impl world::Destructible {
+
+    ///
+    /// Which damaged texture to show at `fraction` of full health, None while undamaged
+    ///
+    pub fn stage_for(&self, fraction: f32) -> Option<usize> {
+        if fraction >= 1.0 || self.damaged_textures.is_empty() {
+            return None;
+        }
+        let stages = self.damaged_textures.len();
+        Some(cmp::min(((1.0 - fraction.max(0.0)) * stages as f32) as usize, stages - 1))
+    }
+}
+
+///
+/// Applies damage events, knockback, invulnerability flashing, death and respawning.
+/// Entities without lives are removed once their death animation has played,
+/// destructible tiles included.
+///
+pub struct HealthSystem;
+
+impl world::System for HealthSystem {
+
+    fn update(&mut self, control_state: &world::ControlState, components: &mut world::Components, entities: &mut Vec<world::Entity>) {
+
+        let now = time::precise_time_s();
+
+        // Bullets deal damage to whatever they hit
+        let mut hits = Vec::new();
+        for entity in entities.iter() {
+            if let (Some(bullet_id), Some(receiver_id)) = (entity.bullet, entity.event_receiver) {
+                let bullet = components.bullet.get(bullet_id);
+                let receiver = components.event_receiver.get(receiver_id);
+
+                for event in receiver.event_queue.iter() {
+                    if let world::Event::Collision(_, other_id) = *event {
+                        let knockback = match entity.dynamic_body {
+                            Some(body_id) => {
+                                let body = components.dynamic_body.get(body_id);
+                                if body.vx != 0.0 || body.vy != 0.0 {
+                                    vec2_scale(vec2_normalized([body.vx, body.vy]), bullet.knockback)
+                                } else {
+                                    [0.0, 0.0]
+                                }
+                            },
+                            None => [0.0, 0.0],
+                        };
+                        hits.push((other_id, world::Event::Damage(bullet.damage, knockback)));
+                    }
+                }
+            }
+        }
+
+        for (collider_id, event) in hits.into_iter() {
+            for entity in entities.iter() {
+                if let (Some(target_collider), Some(_), Some(receiver_id)) = (entity.collider, entity.health, entity.event_receiver) {
+                    if target_collider == collider_id {
+                        components.event_receiver.get_mut(receiver_id).post_event(event);
+                    }
+                }
+            }
+        }
+
+        let mut dead_entities = Vec::new();
+        let mut points = 0;
+
+        for (index, entity) in entities.iter().enumerate() {
+            if let (Some(health_id), Some(receiver_id)) = (entity.health, entity.event_receiver) {
+
+                let health = components.health.get_mut(health_id);
+                let receiver = components.event_receiver.get_mut(receiver_id);
+
+                let was_dead = health.dead;
+                let mut hurt = false;
+
+                for event in receiver.event_queue.iter() {
+                    match *event {
+                        world::Event::Damage(amount, knockback) => {
+                            if health.apply_damage(amount, now) {
+                                hurt = true;
+                                if let Some(body_id) = entity.dynamic_body {
+                                    let body = components.dynamic_body.get_mut(body_id);
+                                    body.vx += knockback[0];
+                                    body.vy += knockback[1];
+                                }
+                            }
+                        },
+                        world::Event::Checkpoint(point) => health.respawn_point = point,
+                        _ => {},
+                    }
+                }
+
+                if health.dead && !was_dead {
+                    receiver.post_event(world::Event::Died);
+
+                    if entity.enemy.is_some() {
+                        points += ENEMY_POINTS;
+                    }
+                    if entity.hive.is_some() {
+                        points += HIVE_POINTS;
+                    }
+
+                    if let (Some(source_id), Some(sound)) = (entity.audio_source, health.death_sound) {
+                        components.audio_source.get_mut(source_id).play(sound);
+                    }
+
+                    if let (Some(animator_id), Some(anim)) = (entity.sprite_animator, health.death_anim.as_ref()) {
+                        let animator = components.sprite_animator.get_mut(animator_id);
+                        animator.animation = anim.clone();
+                        animator.start_time = now;
+                    }
+
+                    if let Some(body_id) = entity.dynamic_body {
+                        let body = components.dynamic_body.get_mut(body_id);
+                        body.vx = 0.0;
+                        body.vy = 0.0;
+                    }
+                } else if hurt {
+                    if let (Some(source_id), Some(sound)) = (entity.audio_source, health.hurt_sound) {
+                        components.audio_source.get_mut(source_id).play(sound);
+                    }
+                }
+
+                // Show how damaged destructible tiles are
+                if hurt {
+                    if let (Some(destructible_id), Some(sprite_id)) = (entity.destructible, entity.sprite_renderer) {
+                        let destructible = components.destructible.get_mut(destructible_id);
+                        let stage = destructible.stage_for(health.current / health.max);
+                        if stage != destructible.stage {
+                            destructible.stage = stage;
+                            if let Some(stage) = stage {
+                                let texture = destructible.damaged_textures[stage].clone();
+                                components.sprite_renderer.get_mut(sprite_id).sprite.set_texture(texture);
+                            }
+                        }
+                    }
+                }
+
+                // Flash while invulnerable
+                if let Some(sprite_id) = entity.sprite_renderer {
+                    let sprite = &mut components.sprite_renderer.get_mut(sprite_id).sprite;
+                    if health.is_invulnerable(now) && !health.dead {
+                        let phase = ((health.invulnerable_until - now) / health.flash_interval as f64) as i64;
+                        sprite.set_visible(phase % 2 == 0);
+                    } else {
+                        sprite.set_visible(true);
+                    }
+                }
+
+                if health.dead && now - health.death_time > health.respawn_delay as f64 {
+                    match health.lives {
+                        Some(lives) if lives > 0 => {
+                            health.lives = Some(lives - 1);
+                            health.revive(now);
+
+                            if let Some(position_id) = entity.position {
+                                let position = components.position.get_mut(position_id);
+                                position.x = health.respawn_point[0];
+                                position.y = health.respawn_point[1];
+                            }
+
+                            if let Some(body_id) = entity.dynamic_body {
+                                let body = components.dynamic_body.get_mut(body_id);
+                                body.vx = 0.0;
+                                body.vy = 0.0;
+                            }
+                        },
+                        Some(_) => receiver.post_event(world::Event::GameOver),
+                        None => dead_entities.push(index),
+                    }
+                }
+            }
+        }
+
+        // Kills score for the player, there's only ever one
+        if points > 0 {
+            for entity in entities.iter() {
+                if let Some(score_id) = entity.score {
+                    components.score.get_mut(score_id).points += points;
+                }
+            }
+        }
+
+        // Remove dead entities, last first so indices stay valid
+        while let Some(index) = dead_entities.pop() {
+            entities.remove(index);
+        }
+    }
+
+    fn render(&mut self, context: &Context, gl: &mut GlGraphics, components: &mut world::Components, entities: &mut Vec<world::Entity>) {
+        // not implemented
+    }
+}
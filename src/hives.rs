@@ -0,0 +1,325 @@
+use graphics::Context;
+use opengl_graphics::GlGraphics;
+use std::num::Float;
+use std::rc::Rc;
+use id::Id;
+
+use assets::{AssetManager, SharedTexture};
+use audio::SoundId;
+use error::{self, Error};
+use world;
+use world::{Hive, WaveDefinition};
+use data;
+use physics;
+use time;
+use units;
+
+impl Hive {
+
+    pub fn new(waves: Vec<Rc<WaveDefinition>>) -> Hive {
+        let delay = waves.first().map(|wave| wave.delay).unwrap_or(0.0);
+        Hive {
+            waves: waves,
+            current: 0,
+            spawned: 0,
+            timer: delay,
+            roaches: Vec::new(),
+        }
+    }
+
+    ///
+    /// Advance the hive's timer by `dt` seconds, returning the wave to spawn
+    /// a roach from if one is due. Waves move on once the last one's roaches
+    /// are all dead.
+    ///
+    pub fn update(&mut self, dt: f32) -> Option<Rc<WaveDefinition>> {
+
+        let wave = match self.waves.get(self.current) {
+            Some(wave) => wave.clone(),
+            None => return None,
+        };
+
+        self.timer -= dt;
+
+        if let Some(count) = wave.count {
+            if self.spawned >= count {
+                if self.roaches.is_empty() {
+                    self.current += 1;
+                    self.spawned = 0;
+                    if let Some(next) = self.waves.get(self.current) {
+                        self.timer = next.delay;
+                    }
+                }
+                return None;
+            }
+        }
+
+        if self.timer > 0.0 || self.roaches.len() >= wave.max_alive as usize {
+            return None;
+        }
+
+        self.spawned += 1;
+        self.timer = wave.interval;
+        Some(wave)
+    }
+}
+
+fn parse_wave(section: &data::Section, texture: SharedTexture, hurt_sound: SoundId, death_sound: SoundId) -> Result<WaveDefinition, String> {
+
+    // 0 or no count means spawn forever
+    let count = match try!(section.get_or("count", 0u32)) {
+        0 => None,
+        count => Some(count),
+    };
+
+    Ok(WaveDefinition {
+        name: section.name.clone(),
+        count: count,
+        delay: try!(section.get_or("delay", 0.0)),
+        interval: try!(section.require("interval")),
+        max_alive: try!(section.get_or("max_alive", 5)),
+        roach_health: try!(section.get_or("health", 1.0)),
+        roach_speed: units::tiles(try!(section.get_or("speed", 0.05))),
+        roach_texture: texture,
+        roach_hurt_sound: hurt_sound,
+        roach_death_sound: death_sound,
+    })
+}
+
+///
+/// Load wave definitions from a data file, one [section] per wave
+///
+pub fn load_waves(name: &str, assets: &mut AssetManager) -> error::Result<Vec<Rc<WaveDefinition>>> {
+
+    let path = assets.path(name);
+    let data_error = |e: String| Error::Data(path.display().to_string(), e);
+
+    let sections = try!(data::load(&path));
+
+    let mut waves = Vec::new();
+    for section in sections.iter() {
+
+        let sprite = try!(assets.load_texture(section.get("sprite").unwrap_or("SpaceRat.png")));
+        let texture = assets.shared_texture(sprite);
+
+        let hurt_sound = try!(assets.load_sound(section.get("hurt_sound").unwrap_or("RoachHurt.wav")));
+        let death_sound = try!(assets.load_sound(section.get("death_sound").unwrap_or("RoachDeath.wav")));
+        let hurt_sound = assets.sound(hurt_sound).id;
+        let death_sound = assets.sound(death_sound).id;
+
+        let wave = try!(parse_wave(section, texture, hurt_sound, death_sound).map_err(&data_error));
+        waves.push(Rc::new(wave));
+    }
+
+    if waves.len() == 0 {
+        return Err(data_error("no waves defined".to_string()));
+    }
+
+    Ok(waves)
+}
+
+///
+/// Waves named in a comma separated list, in order
+///
+pub fn find_waves(names: &str, waves: &[Rc<WaveDefinition>]) -> Result<Vec<Rc<WaveDefinition>>, String> {
+    names.split(',')
+        .map(|name| name.trim())
+        .filter(|name| !name.is_empty())
+        .map(|name| match waves.iter().find(|wave| wave.name == name) {
+            Some(wave) => Ok(wave.clone()),
+            None => Err(format!("unknown wave '{}'", name)),
+        })
+        .collect()
+}
+
+fn spawn_roach(wave: &WaveDefinition, position: world::Position, components: &mut world::Components) -> world::Entity {
+
+    let scuttle_anim = world::SpriteAnimation {
+        frames: vec![(0,0), (1,0)],
+        frame_size: [32, 32],
+        frame_duration: 0.1,
+    };
+
+    let death_anim = world::SpriteAnimation {
+        frames: vec![(0,2), (1,2)],
+        frame_size: [32, 32],
+        frame_duration: 0.15,
+    };
+
+    // Removed once the death animation has played through
+    let mut health = world::Health::new(wave.roach_health, [position.x, position.y]);
+    health.invulnerability = 0.0;
+    health.respawn_delay = 0.3;
+    health.hurt_sound = Some(wave.roach_hurt_sound);
+    health.death_sound = Some(wave.roach_death_sound);
+    health.death_anim = Some(death_anim);
+
+    world::Entity {
+        position: Some(components.position.add(position)),
+        sprite_renderer: Some(components.sprite_renderer.add(world::SpriteRenderer::from_texture_region(wave.roach_texture.get(), [0, 0, 32, 32]))),
+        sprite_animator: Some(components.sprite_animator.add(world::SpriteAnimator {
+            animation: scuttle_anim,
+            start_time: time::precise_time_s(),
+        })),
+        player_controller: None,
+        camera_target: None,
+        camera: None,
+        collider: Some(components.collider.add(world::AABBCollider {
+            width: units::tiles(0.75),
+            height: units::tiles(0.5),
+            layer: physics::LAYER_ENEMY,
+            mask: physics::LAYER_STATIC | physics::LAYER_PLAYER | physics::LAYER_PLAYER_PROJECTILE,
+            sensor: false,
+        })),
+        dynamic_body: Some(components.dynamic_body.add(world::DynamicBody::new(0.0, 0.0))),
+        audio_source: Some(components.audio_source.add(world::AudioSource::new())),
+        weapon: None,
+        bullet: None,
+        event_receiver: Some(components.event_receiver.add(world::EventReceiver::new())),
+        particle_emitter: None,
+        health: Some(components.health.add(health)),
+        score: None,
+        level: None,
+        enemy: Some(components.enemy.add(world::Enemy { speed: wave.roach_speed })),
+        projectile_pool: None,
+        trigger: None,
+        path_follower: None,
+        destructible: None,
+        hive: None,
+    }
+}
+
+///
+/// Spawns roaches from hives, steers them towards the player, and completes
+/// the level once every hive has been destroyed and every roach killed.
+///
+/// Goes before physics so new roaches move on the update they appear.
+///
+pub struct HiveSystem {
+    last_update: f64,
+    /// Colliders of roaches that aren't dead
+    alive: Vec<Id<world::AABBCollider>>,
+    /// Hive and wave for each roach due this update
+    spawns: Vec<(Id<Hive>, Rc<WaveDefinition>, world::Position)>,
+}
+
+impl HiveSystem {
+    pub fn new() -> HiveSystem {
+        HiveSystem {
+            last_update: time::precise_time_s(),
+            alive: Vec::new(),
+            spawns: Vec::new(),
+        }
+    }
+}
+
+impl world::System for HiveSystem {
+
+    fn update(&mut self, control_state: &world::ControlState, components: &mut world::Components, entities: &mut Vec<world::Entity>) {
+
+        let now = time::precise_time_s();
+        let dt = (now - self.last_update) as f32;
+        self.last_update = now;
+
+        // Dying roaches no longer count against their hive
+        self.alive.clear();
+        let mut player = None;
+
+        for entity in entities.iter() {
+            if let (Some(_), Some(collider_id), Some(health_id)) = (entity.enemy, entity.collider, entity.health) {
+                if !components.health.get(health_id).dead {
+                    self.alive.push(collider_id);
+                }
+            }
+            if let (Some(_), Some(position_id), Some(receiver_id)) = (entity.player_controller, entity.position, entity.event_receiver) {
+                player = Some((*components.position.get(position_id), receiver_id));
+            }
+        }
+
+        let mut hives_left = 0;
+
+        for entity in entities.iter() {
+            if let (Some(hive_id), Some(position_id)) = (entity.hive, entity.position) {
+
+                hives_left += 1;
+
+                if let Some(health_id) = entity.health {
+                    if components.health.get(health_id).dead {
+                        continue;
+                    }
+                }
+
+                let hive = components.hive.get_mut(hive_id);
+                let alive = &self.alive;
+                hive.roaches.retain(|collider_id| alive.contains(collider_id));
+
+                if let Some(wave) = hive.update(dt) {
+                    self.spawns.push((hive_id, wave, *components.position.get(position_id)));
+                }
+            }
+        }
+
+        for (hive_id, wave, position) in self.spawns.drain() {
+            let roach = spawn_roach(&wave, position, components);
+            let collider_id = roach.collider.unwrap();
+            components.hive.get_mut(hive_id).roaches.push(collider_id);
+            self.alive.push(collider_id);
+            entities.push(roach);
+        }
+
+        // Roaches scuttle towards the player, unless knocked back
+        if let Some((target, _)) = player {
+            for entity in entities.iter() {
+                if let (Some(enemy_id), Some(position_id), Some(body_id)) = (entity.enemy, entity.position, entity.dynamic_body) {
+
+                    if let Some(health_id) = entity.health {
+                        if components.health.get(health_id).is_stunned(now) {
+                            continue;
+                        }
+                    }
+
+                    let speed = components.enemy.get(enemy_id).speed;
+                    let offset = target.x - components.position.get(position_id).x;
+                    let body = components.dynamic_body.get_mut(body_id);
+
+                    body.vx = if offset.abs() < speed { 0.0 } else { speed * offset.signum() };
+
+                    if let Some(sprite_id) = entity.sprite_renderer {
+                        let sprite = &mut components.sprite_renderer.get_mut(sprite_id).sprite;
+                        if body.vx > 0.0 {
+                            sprite.set_flip_x(false);
+                        } else if body.vx < 0.0 {
+                            sprite.set_flip_x(true);
+                        }
+                    }
+                }
+            }
+        }
+
+        if hives_left > 0 || !self.alive.is_empty() {
+            return;
+        }
+
+        for entity in entities.iter() {
+            if let Some(level_id) = entity.level {
+                let level = components.level.get_mut(level_id);
+                if level.hives > 0 && !level.cleared {
+                    level.cleared = true;
+                    if let Some((_, receiver_id)) = player {
+                        components.event_receiver.get_mut(receiver_id).post_event(world::Event::LevelComplete);
+                    }
+                }
+            }
+        }
+    }
+
+    fn set_paused(&mut self, paused: bool, components: &mut world::Components, entities: &mut Vec<world::Entity>) {
+        if !paused {
+            self.last_update = time::precise_time_s();
+        }
+    }
+
+    fn render(&mut self, context: &Context, gl: &mut GlGraphics, components: &mut world::Components, entities: &mut Vec<world::Entity>) {
+        // not implemented
+    }
+}
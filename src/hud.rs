@@ -0,0 +1,159 @@
+use graphics::Context;
+use opengl_graphics::GlGraphics;
+use std::num::Float;
+
+use world;
+use text::BitmapFont;
+use time;
+use units;
+
+const TEXT_COLOR: [f32; 4] = [1.0, 1.0, 1.0, 1.0];
+const BAR_BACKGROUND: [f32; 4] = [0.0, 0.0, 0.0, 0.6];
+const HEALTH_COLOR: [f32; 4] = [0.8, 0.1, 0.1, 1.0];
+const HEAT_COLOR: [f32; 4] = [1.0, 0.6, 0.1, 1.0];
+const OVERHEAT_COLOR: [f32; 4] = [1.0, 0.1, 0.0, 1.0];
+const CHARGE_COLOR: [f32; 4] = [0.3, 0.7, 1.0, 1.0];
+
+const MARGIN: f64 = 8.0;
+const BAR_WIDTH: f64 = 96.0;
+const BAR_HEIGHT: f64 = 8.0;
+
+///
+/// Draws health, the current weapon with its heat and charge, score, remaining roaches and hives, the level name and
+/// any prompts the player is in, in screen space. Values come from the first
+/// player's components.
+///
+pub struct HudSystem {
+    font: BitmapFont,
+}
+
+impl HudSystem {
+    pub fn new() -> HudSystem {
+        HudSystem {
+            font: BitmapFont::new(2.0),
+        }
+    }
+
+    fn draw_bar(&self, label: &str, fraction: f32, color: [f32; 4], x: f64, y: f64, context: &Context, gl: &mut GlGraphics) {
+
+        use graphics::*;
+
+        self.font.draw(label, x, y, TEXT_COLOR, context, gl);
+
+        let bar_x = x + self.font.width(label) + MARGIN / 2.0;
+        let bar_y = y + (self.font.height() - BAR_HEIGHT) / 2.0;
+        let fraction = fraction.max(0.0).min(1.0) as f64;
+
+        rectangle(BAR_BACKGROUND, [bar_x, bar_y, BAR_WIDTH, BAR_HEIGHT], context.transform, gl);
+        rectangle(color, [bar_x, bar_y, BAR_WIDTH * fraction, BAR_HEIGHT], context.transform, gl);
+    }
+}
+
+impl world::System for HudSystem {
+
+    fn update(&mut self, control_state: &world::ControlState, components: &mut world::Components, entities: &mut Vec<world::Entity>) {
+        // not implemented
+    }
+
+    fn render(&mut self, context: &Context, gl: &mut GlGraphics, components: &mut world::Components, entities: &mut Vec<world::Entity>) {
+        // not implemented
+    }
+
+    fn render_ui(&mut self, context: &Context, gl: &mut GlGraphics, components: &mut world::Components, entities: &mut Vec<world::Entity>) {
+
+        let screen_width = units::VIRTUAL_WIDTH as f64;
+        let line_height = self.font.height() + MARGIN / 2.0;
+
+        let roach_count = entities.iter().filter(|entity| entity.enemy.is_some()).count();
+        let hive_count = entities.iter().filter(|entity| entity.hive.is_some()).count();
+        let mut player_collider = None;
+
+        for entity in entities.iter() {
+            if let Some(_) = entity.player_controller {
+
+                player_collider = entity.collider;
+
+                let mut y = MARGIN;
+
+                if let Some(health_id) = entity.health {
+                    let health = components.health.get(health_id);
+                    self.draw_bar("HP", health.current / health.max, HEALTH_COLOR, MARGIN, y, context, gl);
+                    y += line_height;
+
+                    if let Some(lives) = health.lives {
+                        self.font.draw(&format!("LIVES {}", lives)[..], MARGIN, y, TEXT_COLOR, context, gl);
+                        y += line_height;
+                    }
+                }
+
+                if let Some(weapon_id) = entity.weapon {
+                    let weapon = components.weapon.get(weapon_id);
+                    let definition = weapon.definition();
+
+                    self.font.draw(&definition.name[..], MARGIN, y, TEXT_COLOR, context, gl);
+                    y += line_height;
+
+                    let color = if weapon.overheated { OVERHEAT_COLOR } else { HEAT_COLOR };
+                    self.draw_bar("HEAT", weapon.heat, color, MARGIN, y, context, gl);
+                    y += line_height;
+
+                    // Charge shots show how long they've been held
+                    if let Some(start) = weapon.charge_start {
+                        let charge = if definition.charge_time > 0.0 {
+                            (time::precise_time_s() - start) as f32 / definition.charge_time
+                        } else {
+                            1.0
+                        };
+                        self.draw_bar("CHARGE", charge, CHARGE_COLOR, MARGIN, y, context, gl);
+                    }
+                }
+
+                if let Some(score_id) = entity.score {
+                    let score = components.score.get(score_id);
+                    let text = format!("SCORE {:06}", score.points);
+                    let x = screen_width - MARGIN - self.font.width(&text[..]);
+                    self.font.draw(&text[..], x, MARGIN, TEXT_COLOR, context, gl);
+                }
+
+                break;
+            }
+        }
+
+        let roach_text = format!("ROACHES {}", roach_count);
+        let x = screen_width - MARGIN - self.font.width(&roach_text[..]);
+        self.font.draw(&roach_text[..], x, MARGIN + line_height, TEXT_COLOR, context, gl);
+
+        if hive_count > 0 {
+            let hive_text = format!("HIVES {}", hive_count);
+            let x = screen_width - MARGIN - self.font.width(&hive_text[..]);
+            self.font.draw(&hive_text[..], x, MARGIN + line_height * 2.0, TEXT_COLOR, context, gl);
+        }
+
+        for entity in entities.iter() {
+            if let Some(level_id) = entity.level {
+                let level = components.level.get(level_id);
+                let x = (screen_width - self.font.width(&level.name[..])) / 2.0;
+                self.font.draw(&level.name[..], x, MARGIN, TEXT_COLOR, context, gl);
+                break;
+            }
+        }
+
+        // Tutorial prompts the player is standing in, along the bottom of the screen
+        if let Some(collider_id) = player_collider {
+            let mut y = units::VIRTUAL_HEIGHT as f64 - MARGIN - self.font.height();
+
+            for entity in entities.iter() {
+                if let Some(trigger_id) = entity.trigger {
+                    let trigger = components.trigger.get(trigger_id);
+                    if let world::TriggerAction::Prompt(ref text) = trigger.action {
+                        if trigger.inside.contains(&collider_id) {
+                            let x = (screen_width - self.font.width(&text[..])) / 2.0;
+                            self.font.draw(&text[..], x, y, TEXT_COLOR, context, gl);
+                            y -= line_height;
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
@@ -1,4 +1,4 @@
-#![feature(collections, core, custom_attribute, plugin)]
+#![feature(collections, core, custom_attribute, plugin, fs_time)]
 #![feature(libc)] // tmp hack
 #![plugin(secs)]
 
@@ -21,10 +21,7 @@ extern crate libc;
 use std::cell::RefCell;
 use std::rc::Rc;
 use std::sync::mpsc;
-use std::path::Path;
-
-use openal::al;
-use openal::alc;
+use std::process;
 
 use sdl2_window::Sdl2Window;
 use opengl_graphics::{
@@ -38,12 +35,8 @@ use graphics::Context;
 use input::Button::{Keyboard};
 use input::keyboard::Key;
 
-use std::fs::File;
-
 use sprite::*;
 
-use tiled::parse as tiled_parse;
-
 mod world;
 mod player;
 mod sprites;
@@ -51,20 +44,41 @@ mod physics;
 mod time;
 mod audio;
 mod event;
+mod camera;
+mod units;
+mod particles;
+mod text;
+mod hud;
+mod state;
+mod health;
+mod data;
+mod weapons;
+mod assets;
+mod error;
+mod mixer;
+mod music;
+mod pcm;
+mod triggers;
+mod hives;
+
+/// Seconds to crossfade music between levels and screens
+const MUSIC_FADE: f32 = 1.5;
 
 ///
 /// Load Tiled level, creating entities for each tile instance
+/// Returns the level bounds (left, top, right, bottom) in world units
 ///
-fn init_level(data: &mut world::Components, entities: &mut Vec<world::Entity>) {
+fn init_level(data: &mut world::Components, entities: &mut Vec<world::Entity>, assets: &mut assets::AssetManager) -> error::Result<[f32; 4]> {
 
-    let tmx_file = File::open(&Path::new("./assets/level.tmx")).unwrap();
-    let map = tiled_parse(tmx_file).unwrap();
+    let map_handle = try!(assets.load_map("level.tmx"));
+    let map = assets.map(map_handle);
 
     println!("Map Loaded! Width: {}, Height: {}", map.width, map.height);
 
     // TODO - tileset-specific - keep in tile representation
-    let mut tile_width = 32;
-    let mut tile_height = 32;
+    if map.tile_width as f32 != units::PIXELS_PER_TILE || map.tile_height as f32 != units::PIXELS_PER_TILE {
+        println!("Warning: map tile size {}x{} doesn't match units::PIXELS_PER_TILE", map.tile_width, map.tile_height);
+    }
 
     // Load tileset textures and create shared SpriteRenderer components for each
     // TODO modify to handle tilesheets - need some representation of a texture subregion
@@ -72,21 +86,25 @@ fn init_level(data: &mut world::Components, entities: &mut Vec<world::Entity>) {
     // Maybe just have multiple sprite renderers that share the same Sprite instance
 
     let mut sprite_renderer_ids = Vec::new();
+    let mut tile_textures = Vec::new();
 
     for tileset in map.tilesets.iter() {
         for tileset_image in tileset.images.iter() {
-            let path_string = format!("./assets/{}", tileset_image.source);
-            let path = Path::new(&path_string[..]);
-            let texture = Rc::new(Texture::from_path(&path).unwrap());
+            let texture_handle = try!(assets.load_texture(&tileset_image.source[..]));
+            let texture = assets.texture(texture_handle);
             let sprite_renderer = world::SpriteRenderer::from_texture_region(
                 texture.clone(),
                 [0, 0, 32, 32],
             );
             sprite_renderer_ids.push(data.sprite_renderer.add(sprite_renderer));
+            tile_textures.push(texture);
         }
     }
 
     for layer in map.layers.iter() {
+
+        let destructible = try!(destructible_layer(layer, assets));
+
         for (column, tile_column) in layer.tiles.iter().enumerate() {
             for (row, tile) in tile_column.iter().enumerate() {
 
@@ -94,35 +112,504 @@ fn init_level(data: &mut world::Components, entities: &mut Vec<world::Entity>) {
                     continue;
                 }
 
-                let tile_entity = world::Entity {
-                    position: Some(data.position.add(world::Position {
-                        x: (row * tile_width) as f32,
-                        y: (column * tile_height) as f32,
-                    })),
-                    sprite_renderer: Some(sprite_renderer_ids[(tile - 1) as usize]),
-                    sprite_animator: None,
-                    player_controller: None,
-                    camera_target: None,
-                    collider: Some(data.collider.add(world::AABBCollider { width: 32.0, height: 32.0 })),
-                    dynamic_body: None,
-                    audio_source: None,
-                    weapon: None,
-                    bullet: None,
-                    event_receiver: None,
+                let position = world::Position {
+                    x: units::tiles(row as f32),
+                    y: units::tiles(column as f32),
+                };
+
+                let collider = world::AABBCollider {
+                    width: units::tiles(1.0),
+                    height: units::tiles(1.0),
+                    layer: physics::LAYER_STATIC,
+                    mask: physics::ALL_LAYERS,
+                    sensor: false,
                 };
 
-                // TODO add collision for each tile ...
+                let tile_entity = match destructible {
+                    Some(ref destructible) => spawn_destructible_tile(data, destructible, tile_textures[(tile - 1) as usize].clone(), position, collider),
+                    None => world::Entity {
+                        position: Some(data.position.add(position)),
+                        sprite_renderer: Some(sprite_renderer_ids[(tile - 1) as usize]),
+                        sprite_animator: None,
+                        player_controller: None,
+                        camera_target: None,
+                        camera: None,
+                        collider: Some(data.collider.add(collider)),
+                        dynamic_body: None,
+                        audio_source: None,
+                        weapon: None,
+                        bullet: None,
+                        event_receiver: None,
+                        particle_emitter: None,
+                        health: None,
+                        score: None,
+                        level: None,
+                        enemy: None,
+                        projectile_pool: None,
+                        trigger: None,
+                        path_follower: None,
+                        destructible: None,
+                        hive: None,
+                    },
+                };
 
                 entities.push(tile_entity);
 
             }
         }
     }
+
+    let level_name = match map.properties.get("name") {
+        Some(name) => name.clone(),
+        None => "Level 1".to_string(),
+    };
+
+    let level_music = music_track(&map.properties);
+
+    let waves = try!(hives::load_waves("waves.cfg", assets));
+
+    let mut gravity_zones = Vec::new();
+    let mut hives = 0;
+
+    for object_group in map.object_groups.iter() {
+        for object in object_group.objects.iter() {
+            match &object.obj_type[..] {
+                "gravity" => match gravity_zone(object) {
+                    Some(zone) => gravity_zones.push(zone),
+                    None => println!("Warning: ignoring gravity zone '{}' at {}, {}", object.name, object.x, object.y),
+                },
+                "trigger" | "checkpoint" | "exit" | "hazard" | "music" | "prompt" => match spawn_trigger(data, object) {
+                    Some(trigger_entity) => entities.push(trigger_entity),
+                    None => println!("Warning: ignoring {} '{}' at {}, {}", object.obj_type, object.name, object.x, object.y),
+                },
+                "platform" => {
+                    let sprite = object.properties.get("sprite").map(|sprite| &sprite[..]).unwrap_or("Block.png");
+                    let texture_handle = try!(assets.load_texture(sprite));
+                    match spawn_platform(data, object, assets.texture(texture_handle)) {
+                        Some(platform_entity) => entities.push(platform_entity),
+                        None => println!("Warning: ignoring platform '{}' at {}, {}", object.name, object.x, object.y),
+                    }
+                },
+                "hive" => {
+                    let sprite = object.properties.get("sprite").map(|sprite| &sprite[..]).unwrap_or("Hive.png");
+                    let texture_handle = try!(assets.load_texture(sprite));
+                    match spawn_hive(data, object, &waves[..], assets.texture(texture_handle)) {
+                        Ok(hive_entity) => {
+                            entities.push(hive_entity);
+                            hives += 1;
+                        },
+                        Err(e) => println!("Warning: ignoring hive '{}' at {}, {}: {}", object.name, object.x, object.y, e),
+                    }
+                },
+                _ => {},
+            }
+        }
+    }
+
+    let level = world::Level {
+        name: level_name,
+        music: level_music,
+        gravity_zones: gravity_zones,
+        hives: hives,
+        cleared: false,
+    };
+
+    entities.push(world::Entity {
+        position: None,
+        sprite_renderer: None,
+        sprite_animator: None,
+        player_controller: None,
+        camera_target: None,
+        camera: None,
+        collider: None,
+        dynamic_body: None,
+        audio_source: None,
+        weapon: None,
+        bullet: None,
+        event_receiver: None,
+        particle_emitter: None,
+        health: None,
+        score: None,
+        level: Some(data.level.add(level)),
+        enemy: None,
+        projectile_pool: None,
+        trigger: None,
+        path_follower: None,
+        destructible: None,
+        hive: None,
+    });
+
+    // Tiles are positioned by their centers
+    Ok([
+        -units::tiles(0.5),
+        -units::tiles(0.5),
+        units::tiles(map.width as f32 - 0.5),
+        units::tiles(map.height as f32 - 0.5),
+    ])
+}
+
+///
+/// Settings for the tiles of a layer with a `destructible` property
+///
+struct DestructibleLayer {
+    health: f32,
+    damaged_textures: Vec<Rc<Texture>>,
+    sound: Option<audio::SoundId>,
+}
+
+///
+/// Read a destructible layer's `health` (hits, 3 if not set), `damaged`
+/// textures from least to most damaged, and `sound` played when a tile breaks.
+/// None for ordinary layers.
+///
+fn destructible_layer(layer: &tiled::Layer, assets: &mut assets::AssetManager) -> error::Result<Option<DestructibleLayer>> {
+
+    if !layer.properties.contains_key("destructible") {
+        return Ok(None);
+    }
+
+    let health = match layer.properties.get("health").map(|value| value.parse()) {
+        Some(Ok(health)) => health,
+        Some(Err(_)) => {
+            println!("Warning: bad health on layer '{}', using 3", layer.name);
+            3.0
+        },
+        None => 3.0,
+    };
+
+    let mut damaged_textures = Vec::new();
+    if let Some(names) = layer.properties.get("damaged") {
+        for name in names.split(',').map(|name| name.trim()).filter(|name| !name.is_empty()) {
+            let texture_handle = try!(assets.load_texture(name));
+            damaged_textures.push(assets.texture(texture_handle));
+        }
+    }
+
+    let sound = match layer.properties.get("sound") {
+        Some(name) => {
+            let sound_handle = try!(assets.load_sound(name));
+            Some(assets.sound(sound_handle).id)
+        },
+        None => None,
+    };
+
+    Ok(Some(DestructibleLayer {
+        health: health,
+        damaged_textures: damaged_textures,
+        sound: sound,
+    }))
+}
+
+///
+/// Tile that takes damage from projectiles and is removed when its health runs out.
+/// Each one gets its own sprite so it can show its own damage.
+///
+fn spawn_destructible_tile(data: &mut world::Components, layer: &DestructibleLayer, texture: Rc<Texture>, position: world::Position, collider: world::AABBCollider) -> world::Entity {
+
+    let mut health = world::Health::new(layer.health, [position.x, position.y]);
+    health.invulnerability = 0.0;
+    health.stun = 0.0;
+    health.respawn_delay = 0.0;
+    health.death_sound = layer.sound;
+
+    world::Entity {
+        position: Some(data.position.add(position)),
+        sprite_renderer: Some(data.sprite_renderer.add(world::SpriteRenderer::from_texture_region(texture, [0, 0, 32, 32]))),
+        sprite_animator: None,
+        player_controller: None,
+        camera_target: None,
+        camera: None,
+        collider: Some(data.collider.add(collider)),
+        dynamic_body: None,
+        audio_source: Some(data.audio_source.add(world::AudioSource::new())),
+        weapon: None,
+        bullet: None,
+        event_receiver: Some(data.event_receiver.add(world::EventReceiver::new())),
+        particle_emitter: None,
+        health: Some(data.health.add(health)),
+        score: None,
+        level: None,
+        enemy: None,
+        projectile_pool: None,
+        trigger: None,
+        path_follower: None,
+        destructible: Some(data.destructible.add(world::Destructible {
+            damaged_textures: layer.damaged_textures.clone(),
+            stage: None,
+        })),
+        hive: None,
+    }
+}
+
+///
+/// Bounds of a rectangle object (left, top, right, bottom) in world units
+///
+fn object_bounds(object: &tiled::Object) -> Option<[f32; 4]> {
+    match object.shape {
+        tiled::ObjectShape::Rect { width, height } => {
+            // Tiles are positioned by their centers, objects from the map's corner
+            let left = units::pixels(object.x) - units::tiles(0.5);
+            let top = units::pixels(object.y) - units::tiles(0.5);
+            Some([left, top, left + units::pixels(width), top + units::pixels(height)])
+        },
+        _ => None,
+    }
+}
+
+///
+/// Rectangle with a `direction` of up, down, left or right and an optional `strength`
+///
+fn gravity_zone(object: &tiled::Object) -> Option<world::GravityZone> {
+
+    let bounds = match object_bounds(object) {
+        Some(bounds) => bounds,
+        None => return None,
+    };
+
+    let direction = match object.properties.get("direction").map(|direction| &direction[..]) {
+        Some("up") => [0.0, -1.0],
+        Some("down") => [0.0, 1.0],
+        Some("left") => [-1.0, 0.0],
+        Some("right") => [1.0, 0.0],
+        _ => return None,
+    };
+
+    let strength = match object.properties.get("strength") {
+        Some(value) => match value.parse() {
+            Ok(strength) => strength,
+            Err(_) => return None,
+        },
+        None => 1.0,
+    };
+
+    Some(world::GravityZone { bounds: bounds, direction: direction, strength: strength })
+}
+
+///
+/// Music named by a `music` property, loop points are in seconds
+///
+fn music_track(properties: &tiled::Properties) -> Option<music::MusicTrack> {
+    properties.get("music").map(|name| {
+        let mut track = music::MusicTrack::new(name);
+        if let Some(start) = properties.get("music_loop_start").and_then(|value| value.parse().ok()) {
+            track.loop_start = start;
+        }
+        track.loop_end = properties.get("music_loop_end").and_then(|value| value.parse().ok());
+        track
+    })
+}
+
+///
+/// Sensor entity for a rectangle object, its type picks what it does:
+/// checkpoint, exit, hazard (with `damage`), music (with `music` and loop points),
+/// prompt (with `text`), or trigger for nothing but events
+///
+fn spawn_trigger(data: &mut world::Components, object: &tiled::Object) -> Option<world::Entity> {
+
+    let bounds = match object_bounds(object) {
+        Some(bounds) => bounds,
+        None => return None,
+    };
+
+    let center = [(bounds[0] + bounds[2]) / 2.0, (bounds[1] + bounds[3]) / 2.0];
+
+    let action = match &object.obj_type[..] {
+        "checkpoint" => world::TriggerAction::Checkpoint(center),
+        "exit" => world::TriggerAction::Exit,
+        "hazard" => match object.properties.get("damage").map(|value| value.parse()) {
+            Some(Ok(damage)) => world::TriggerAction::Hazard(damage),
+            Some(Err(_)) => return None,
+            None => world::TriggerAction::Hazard(1.0),
+        },
+        "music" => match music_track(&object.properties) {
+            Some(track) => world::TriggerAction::Music(track),
+            None => return None,
+        },
+        "prompt" => match object.properties.get("text") {
+            Some(text) => world::TriggerAction::Prompt(text.clone()),
+            None => return None,
+        },
+        _ => world::TriggerAction::None,
+    };
+
+    Some(world::Entity {
+        position: Some(data.position.add(world::Position { x: center[0], y: center[1] })),
+        sprite_renderer: None,
+        sprite_animator: None,
+        player_controller: None,
+        camera_target: None,
+        camera: None,
+        collider: Some(data.collider.add(world::AABBCollider {
+            width: bounds[2] - bounds[0],
+            height: bounds[3] - bounds[1],
+            layer: physics::LAYER_TRIGGER,
+            mask: physics::LAYER_PLAYER,
+            sensor: true,
+        })),
+        dynamic_body: None,
+        audio_source: None,
+        weapon: None,
+        bullet: None,
+        event_receiver: Some(data.event_receiver.add(world::EventReceiver::new())),
+        particle_emitter: None,
+        health: None,
+        score: None,
+        level: None,
+        enemy: None,
+        projectile_pool: None,
+        trigger: Some(data.trigger.add(world::Trigger::new(action))),
+        path_follower: None,
+        destructible: None,
+        hive: None,
+    })
+}
+
+///
+/// Numeric property of an object, None if it's there but isn't a number
+///
+fn number_property(object: &tiled::Object, name: &str, default: f32) -> Option<f32> {
+    match object.properties.get(name) {
+        Some(value) => value.parse().ok(),
+        None => Some(default),
+    }
+}
+
+///
+/// Roach hive at a rectangle object's center. `waves` lists wave names from
+/// waves.cfg in the order they're sent, every wave in the file if not set.
+/// `health` is how much damage it takes to destroy.
+///
+fn spawn_hive(data: &mut world::Components, object: &tiled::Object, waves: &[Rc<world::WaveDefinition>], texture: Rc<Texture>) -> Result<world::Entity, String> {
+
+    let bounds = match object_bounds(object) {
+        Some(bounds) => bounds,
+        None => return Err("not a rectangle".to_string()),
+    };
+
+    let waves = match object.properties.get("waves") {
+        Some(names) => try!(hives::find_waves(names, waves)),
+        None => waves.to_vec(),
+    };
+
+    let max_health = match number_property(object, "health", 10.0) {
+        Some(health) => health,
+        None => return Err("bad health".to_string()),
+    };
+
+    let position = world::Position {
+        x: (bounds[0] + bounds[2]) / 2.0,
+        y: (bounds[1] + bounds[3]) / 2.0,
+    };
+
+    let mut health = world::Health::new(max_health, [position.x, position.y]);
+    health.invulnerability = 0.0;
+    health.stun = 0.0;
+    health.respawn_delay = 0.0;
+
+    Ok(world::Entity {
+        position: Some(data.position.add(position)),
+        sprite_renderer: Some(data.sprite_renderer.add(world::SpriteRenderer::from_texture_region(texture, [0, 0, 32, 32]))),
+        sprite_animator: None,
+        player_controller: None,
+        camera_target: None,
+        camera: None,
+        collider: Some(data.collider.add(world::AABBCollider {
+            width: units::tiles(1.0),
+            height: units::tiles(1.0),
+            layer: physics::LAYER_ENEMY,
+            mask: physics::LAYER_STATIC | physics::LAYER_PLAYER | physics::LAYER_PLAYER_PROJECTILE,
+            sensor: false,
+        })),
+        dynamic_body: None,
+        audio_source: None,
+        weapon: None,
+        bullet: None,
+        event_receiver: Some(data.event_receiver.add(world::EventReceiver::new())),
+        particle_emitter: None,
+        health: Some(data.health.add(health)),
+        score: None,
+        level: None,
+        enemy: None,
+        projectile_pool: None,
+        trigger: None,
+        path_follower: None,
+        destructible: None,
+        hive: Some(data.hive.add(world::Hive::new(waves))),
+    })
+}
+
+///
+/// Kinematic platform following a polyline object from its first point.
+/// `width` and `height` are in tiles, `speed` in tiles per update, and with
+/// `loop` set it goes from the last point straight to the first instead of
+/// back along the path.
+///
+fn spawn_platform(data: &mut world::Components, object: &tiled::Object, texture: Rc<Texture>) -> Option<world::Entity> {
+
+    // Polyline points are relative to the object, tiles are positioned by their centers
+    let points: Vec<[f32; 2]> = match object.shape {
+        tiled::ObjectShape::Polyline { ref points } => points.iter()
+            .map(|&(x, y)| [units::pixels(object.x + x) - units::tiles(0.5), units::pixels(object.y + y) - units::tiles(0.5)])
+            .collect(),
+        _ => return None,
+    };
+
+    if points.len() < 2 {
+        return None;
+    }
+
+    let width = match number_property(object, "width", 3.0) { Some(width) => width, None => return None };
+    let height = match number_property(object, "height", 1.0) { Some(height) => height, None => return None };
+    let speed = match number_property(object, "speed", 0.03) { Some(speed) => speed, None => return None };
+    let looping = object.properties.get("loop").map(|value| &value[..] == "true").unwrap_or(false);
+
+    // Stretch one tile's worth of texture over the whole platform
+    let mut sprite_renderer = world::SpriteRenderer::from_texture_region(texture, [0, 0, 32, 32]);
+    let scale = 1.0 / units::pixels_per_unit() as f64;
+    sprite_renderer.sprite.set_scale(scale * width as f64, scale * height as f64);
+
+    let mut body = world::DynamicBody::new(0.0, 0.0);
+    body.kinematic = true;
+    body.gravity_scale = 0.0;
+
+    let start = points[0];
+
+    Some(world::Entity {
+        position: Some(data.position.add(world::Position { x: start[0], y: start[1] })),
+        sprite_renderer: Some(data.sprite_renderer.add(sprite_renderer)),
+        sprite_animator: None,
+        player_controller: None,
+        camera_target: None,
+        camera: None,
+        collider: Some(data.collider.add(world::AABBCollider {
+            width: units::tiles(width),
+            height: units::tiles(height),
+            layer: physics::LAYER_STATIC,
+            mask: physics::ALL_LAYERS,
+            sensor: false,
+        })),
+        dynamic_body: Some(data.dynamic_body.add(body)),
+        audio_source: None,
+        weapon: None,
+        bullet: None,
+        event_receiver: None,
+        particle_emitter: None,
+        health: None,
+        score: None,
+        level: None,
+        enemy: None,
+        projectile_pool: None,
+        trigger: None,
+        path_follower: Some(data.path_follower.add(world::PathFollower::new(points, units::tiles(speed), looping))),
+        destructible: None,
+        hive: None,
+    })
 }
 
-fn spawn_player(data: &mut world::Components) -> world::Entity {
+fn spawn_player(data: &mut world::Components, assets: &mut assets::AssetManager) -> error::Result<world::Entity> {
 
-    let player_sprite_sheet = Rc::new(Texture::from_path(&Path::new("./assets/player.png")).unwrap());
+    let player_texture = try!(assets.load_texture("player.png"));
+    let player_sprite_sheet = assets.texture(player_texture);
 
     let idle_anim = world::SpriteAnimation {
         frames: vec![(0,0)],
@@ -184,6 +671,12 @@ fn spawn_player(data: &mut world::Components) -> world::Entity {
         frame_duration: 0.1,
     };
 
+    let death_anim = world::SpriteAnimation {
+        frames: vec![(0,5)],
+        frame_size: [32, 32],
+        frame_duration: 0.1,
+    };
+
     let sprite_renderer = world::SpriteRenderer::from_texture_region(
         player_sprite_sheet.clone(),
         [0, 0, 32, 32],
@@ -194,17 +687,21 @@ fn spawn_player(data: &mut world::Components) -> world::Entity {
         start_time: time::precise_time_s(),
     };
 
-    // TODO - should generate buffers through AudioSystem
-    let mut jump_sound = al::Buffer::gen();
-    audio::load_buffer("assets/Jump.wav", &mut jump_sound);
+    let jump_sound_handle = try!(assets.load_sound("Jump.wav"));
+    let jump_sound = assets.sound(jump_sound_handle).id;
+
+    let land_sound_handle = try!(assets.load_sound("Land.wav"));
+    let land_sound = assets.sound(land_sound_handle).id;
 
-    let mut land_sound = al::Buffer::gen();
-    audio::load_buffer("assets/Land.wav", &mut land_sound);
+    let hurt_sound_handle = try!(assets.load_sound("Hurt.wav"));
+    let hurt_sound = assets.sound(hurt_sound_handle).id;
+
+    let death_sound_handle = try!(assets.load_sound("Death.wav"));
+    let death_sound = assets.sound(death_sound_handle).id;
 
     let player_controller = world::PlayerController {
-        move_speed: 1.0,
+        move_speed: units::tiles(1.0 / 32.0), // tiles per update
         state: world::PlayerState::OnFloor,
-        ground_check: world::AABBCollider { width: 36.0, height: 36.0 },
         aim_direction: [0.0, 0.0],
 
         idle_anim: idle_anim,
@@ -222,122 +719,296 @@ fn spawn_player(data: &mut world::Components) -> world::Entity {
 
         land_sound: land_sound,
         jump_sound: jump_sound,
+
+        jump_speed: units::tiles(0.2),
+        gravity: units::tiles(0.01),
+        max_fall_speed: units::tiles(0.3),
+        jump_cut: 0.5,
+        coyote_time: 0.1,
+        jump_buffer: 0.15,
+
+        gravity_direction: [0.0, 1.0],
+        crawl_input: [0.0, 0.0],
+        crawl_direction: [0.0, 0.0],
+        coyote_until: 0.0,
+        jump_buffered_until: 0.0,
+        jumping: false,
+
+        corner_turn: None,
+        sprite_rotation: 0.0,
     };
 
-    world::Entity {
-        position: Some(data.position.add(world::Position { x: 64.0, y: 96.0 })),
+    let weapon_definitions = try!(weapons::load_definitions("weapons.cfg", assets));
+
+    // The PlayerSystem does the player's gravity, relative to the surface it left
+    let mut player_body = world::DynamicBody::new(0.0, 0.0);
+    player_body.gravity_scale = 0.0;
+
+    let spawn_point = [units::tiles(2.0), units::tiles(3.0)];
+
+    let mut player_health = world::Health::new(5.0, spawn_point);
+    player_health.lives = Some(3);
+    player_health.hurt_sound = Some(hurt_sound);
+    player_health.death_sound = Some(death_sound);
+    player_health.death_anim = Some(death_anim);
+
+    Ok(world::Entity {
+        position: Some(data.position.add(world::Position { x: spawn_point[0], y: spawn_point[1] })),
         sprite_renderer: Some(data.sprite_renderer.add(sprite_renderer)),
         sprite_animator: Some(data.sprite_animator.add(sprite_animator)),
         player_controller: Some(data.player_controller.add(player_controller)),
         camera_target: Some(data.camera_target.add(world::CameraTarget)),
-        collider: Some(data.collider.add(world::AABBCollider { width: 32.0, height: 32.0 })),
-        dynamic_body: Some(data.dynamic_body.add(world::DynamicBody { vx: 0.0, vy: 0.0 })),
+        camera: None,
+        collider: Some(data.collider.add(world::AABBCollider {
+            width: units::tiles(1.0),
+            height: units::tiles(1.0),
+            layer: physics::LAYER_PLAYER,
+            mask: physics::LAYER_STATIC | physics::LAYER_ENEMY | physics::LAYER_ENEMY_PROJECTILE | physics::LAYER_TRIGGER,
+            sensor: false,
+        })),
+        dynamic_body: Some(data.dynamic_body.add(player_body)),
         audio_source: Some(data.audio_source.add(world::AudioSource::new())),
-        weapon: Some(data.weapon.add(world::Weapon::new())),
+        weapon: Some(data.weapon.add(world::Weapon::new(weapon_definitions))),
         bullet: None,
         event_receiver: Some(data.event_receiver.add(world::EventReceiver::new())),
+        particle_emitter: None,
+        health: Some(data.health.add(player_health)),
+        score: Some(data.score.add(world::Score { points: 0 })),
+        level: None,
+        enemy: None,
+        projectile_pool: None,
+        trigger: None,
+        path_follower: None,
+        destructible: None,
+        hive: None,
+    })
+}
+
+fn spawn_camera(data: &mut world::Components, bounds: [f32; 4]) -> world::Entity {
+
+    let mut camera = world::Camera::new(units::tiles(2.0), units::tiles(3.0));
+    camera.bounds = Some(bounds);
+
+    world::Entity {
+        position: None,
+        sprite_renderer: None,
+        sprite_animator: None,
+        player_controller: None,
+        camera_target: None,
+        camera: Some(data.camera.add(camera)),
+        collider: None,
+        dynamic_body: None,
+        audio_source: None,
+        weapon: None,
+        bullet: None,
+        event_receiver: Some(data.event_receiver.add(world::EventReceiver::new())),
+        particle_emitter: None,
+        health: None,
+        score: None,
+        level: None,
+        enemy: None,
+        projectile_pool: None,
+        trigger: None,
+        path_follower: None,
+        destructible: None,
+        hive: None,
     }
 }
 
-fn get_camera_context(world: &world::World, viewport_width: u32, viewport_height: u32) -> Context {
+fn get_camera_context(world: &world::World) -> Context {
 
-    let mut camera = [0.0, 0.0]; // position of camera relative to world origin (in pixels)
-    // Set camera to Position of first entity with a CameraTarget component
+    // Use the first entity with a Camera component
     for entity in world.entities.iter() {
-        match (entity.position, entity.camera_target) {
-            (Some(position_id), Some(target_id)) => {
-                let position = world.data.position.get(position_id);
-                camera[0] = position.x as f64;
-                camera[1] = position.y as f64;
-                break;
-            },
-            _ => {},
+        if let Some(camera_id) = entity.camera {
+            return world.data.camera.get(camera_id).get_context();
         }
     }
 
-    // Y axis needs to be down, otherwise `sprite` gets messed...
-    // TODO consider flipping + scaling every sprite?
-    // Also would be nice to have 1 world unit = 1 tile, instead of pixel
-
-    let sx = 2.0 / viewport_width as f64;
-    let sy = 2.0 / viewport_height as f64;
-    let tx = -camera[0] * 2.0 / viewport_width as f64;
-    let ty = -camera[1] * 2.0 / viewport_height as f64;
-    let mat = [[ sx,  0.0, tx],
-        [ 0.0, -sy, -ty]];
-
+    // No camera, just center on the world origin
     let mut context = Context::new();
+    let viewport = [units::VIRTUAL_WIDTH as f32, units::VIRTUAL_HEIGHT as f32];
+    let mat = camera::view_matrix([0.0, 0.0], viewport, units::pixels_per_unit());
     context.view = mat;
     context.transform = mat;
     context
-
 }
 
-fn main() {
+///
+/// Screen space context in virtual pixels, origin at the top left
+///
+fn get_ui_context() -> Context {
+    let sx = 2.0 / units::VIRTUAL_WIDTH as f64;
+    let sy = 2.0 / units::VIRTUAL_HEIGHT as f64;
+    let mat = [[ sx,  0.0, -1.0],
+               [ 0.0, -sy,  1.0]];
 
-    let mut systems: Vec<Box<world::System>> = vec![
-        Box::new(player::PlayerSystem),
-        Box::new(sprites::SpriteSystem),
-        Box::new(physics::PhysicsSystem),
-        Box::new(audio::AudioSystem::new()),
-    ];
+    let mut context = Context::new();
+    context.view = mat;
+    context.transform = mat;
+    context
+}
 
-    let (width, height) = (640, 480);
-    let opengl = OpenGL::_3_2;
-    let window = Sdl2Window::new(
-        opengl,
-        piston::window::WindowSettings {
-            title: "Space Roach Exterminator III".to_string(),
-            size: [width, height],
-            fullscreen: false,
-            exit_on_esc: true,
-            samples: 0,
-        }
-    );
+///
+/// Create a fresh world with the level, player and camera
+///
+fn load_world(assets: &mut assets::AssetManager) -> error::Result<world::World> {
 
     let mut world = world::World::new();
 
-    init_level(&mut world.data, &mut world.entities);
-    let player_entity = spawn_player(&mut world.data);
+    let level_bounds = try!(init_level(&mut world.data, &mut world.entities, assets));
+    let player_entity = try!(spawn_player(&mut world.data, assets));
     world.entities.push(player_entity);
+    let camera_entity = spawn_camera(&mut world.data, level_bounds);
+    world.entities.push(camera_entity);
 
-    let mut music_source = world::AudioSource::new();
-    music_source.load_file("assets/space_roaches.wav");
-    music_source.play();
-    let music_player = world::Entity {
+    let projectile_pool = world::Entity {
         position: None,
         sprite_renderer: None,
         sprite_animator: None,
         player_controller: None,
         camera_target: None,
+        camera: None,
         collider: None,
         dynamic_body: None,
-        audio_source: Some(world.data.audio_source.add(music_source)),
+        audio_source: None,
         weapon: None,
         bullet: None,
         event_receiver: None,
+        particle_emitter: None,
+        health: None,
+        score: None,
+        level: None,
+        enemy: None,
+        projectile_pool: Some(world.data.projectile_pool.add(world::ProjectilePool::new())),
+        trigger: None,
+        path_follower: None,
+        destructible: None,
+        hive: None,
     };
-    world.entities.push(music_player);
+    world.entities.push(projectile_pool);
+
+    Ok(world)
+}
+
+///
+/// Music for the level in the world, if it has any
+///
+fn level_music(world: &world::World) -> Option<music::MusicTrack> {
+    for entity in world.entities.iter() {
+        if let Some(level_id) = entity.level {
+            return world.data.level.get(level_id).music.clone();
+        }
+    }
+    None
+}
+
+///
+/// Crossfade to the level's music while it's on screen, fade out on the other screens
+///
+fn update_music(music: &mut music::MusicPlayer, state: state::GameState, world: &world::World, assets: &assets::AssetManager) {
+
+    let now = time::precise_time_s();
+
+    music.set_paused(state == state::GameState::Paused);
+
+    match (state, level_music(world)) {
+        (state::GameState::Playing, Some(track)) | (state::GameState::Paused, Some(track)) => {
+            music.play(&track, &assets.path(&track.name[..]), MUSIC_FADE, now);
+        },
+        _ => music.stop(MUSIC_FADE, now),
+    }
+}
+
+///
+/// Report a fatal loading error and quit, there's nothing to fall back to
+///
+fn or_exit<T>(result: error::Result<T>) -> T {
+    match result {
+        Ok(value) => value,
+        Err(e) => {
+            println!("Error: {}", e);
+            process::exit(1);
+        }
+    }
+}
+
+fn main() {
+
+    let (width, height) = (units::VIRTUAL_WIDTH, units::VIRTUAL_HEIGHT);
+    let opengl = OpenGL::_3_2;
+    let window = Sdl2Window::new(
+        opengl,
+        piston::window::WindowSettings {
+            title: "Space Roach Exterminator III".to_string(),
+            size: [width, height],
+            fullscreen: false,
+            exit_on_esc: false,
+            samples: 0,
+        }
+    );
+
+    let audio_backend = audio::open_backend();
+
+    // Textures can only be loaded once the window has created a GL context
+    let mut assets = assets::AssetManager::new(audio_backend.clone());
+
+    // Events posted during a frame are readable by every system during the next one,
+    // so the EventSystem goes last.
+    // Particles and health come before weapons so bullet impacts are seen before bullets are removed.
+    // Each system is tagged with the group states use to pick what runs.
+    let mut systems: Vec<(state::SystemSet, Box<world::System>)> = vec![
+        (state::SYSTEMS_EFFECTS, Box::new(or_exit(particles::ParticleSystem::new(&mut assets)))),
+        (state::SYSTEMS_GAMEPLAY, Box::new(health::HealthSystem)),
+        (state::SYSTEMS_GAMEPLAY, Box::new(player::PlayerSystem)),
+        (state::SYSTEMS_GAMEPLAY, Box::new(weapons::WeaponSystem::new())),
+        (state::SYSTEMS_EFFECTS, Box::new(camera::CameraSystem::new())),
+        (state::SYSTEMS_EFFECTS, Box::new(sprites::SpriteSystem)),
+        (state::SYSTEMS_GAMEPLAY, Box::new(hives::HiveSystem::new())),
+        (state::SYSTEMS_GAMEPLAY, Box::new(physics::PhysicsSystem)),
+        (state::SYSTEMS_GAMEPLAY, Box::new(triggers::TriggerSystem::new())),
+        (state::SYSTEMS_EFFECTS, Box::new(audio::AudioSystem::new(audio_backend.clone()))),
+        (state::SYSTEMS_HUD, Box::new(hud::HudSystem::new())),
+        (state::SYSTEMS_EFFECTS, Box::new(event::EventSystem)),
+    ];
+
+    let mut world = or_exit(load_world(&mut assets));
+    let mut states = state::StateStack::new(state::GameState::Title);
+    let font = text::BitmapFont::new(3.0);
+    let mut last_reload_check = time::precise_time_s();
+    let mut music = music::MusicPlayer::new(audio_backend.clone());
+    let mut current_level_music = level_music(&world);
+
+    // Start on the title screen with whatever it doesn't run frozen
+    use world::System;
+    let updated = states.current().updated_systems();
+    for entry in systems.iter_mut() {
+        if entry.0 & updated == 0 {
+            entry.1.set_paused(true, &mut world.data, &mut world.entities);
+        }
+    }
 
     let ref mut gl = GlGraphics::new(opengl);
     let window = Rc::new(RefCell::new(window));
 
-    let mut control_state = world::ControlState {
-        move_left: false,
-        move_right: false,
-        move_up: false,
-        move_down: false,
-        aim_left: false,
-        aim_right: false,
-        aim_up: false,
-        aim_down: false,
-    };
+    let mut control_state = world::ControlState::new();
 
     for e in piston::events(window) {
         use piston::event::{ RenderEvent, PressEvent, ReleaseEvent };
-        use world::System;
+
+        let mut transition = state::Transition::None;
+
+        // Menus and pause screens don't feed the player, so nothing is left latched when play resumes
+        let gameplay = states.current().updated_systems() & state::SYSTEMS_GAMEPLAY != 0;
 
         e.press(|button| {
+            if let Keyboard(key) = button {
+                transition = states.current().key_transition(key);
+            }
+
+            if !gameplay {
+                return;
+            }
+
             match button {
                 Keyboard(Key::A) => control_state.move_left = true,
                 Keyboard(Key::D) => control_state.move_right = true,
@@ -347,6 +1018,12 @@ fn main() {
                 Keyboard(Key::Right) => control_state.aim_right = true,
                 Keyboard(Key::Up) => control_state.aim_up = true,
                 Keyboard(Key::Down) => control_state.aim_down = true,
+                Keyboard(Key::Space) => {
+                    control_state.jump = true;
+                    control_state.jump_pressed = true;
+                },
+                Keyboard(Key::E) => control_state.next_weapon = true,
+                Keyboard(Key::Q) => control_state.previous_weapon = true,
                 _ => {}
             }
         });
@@ -361,31 +1038,134 @@ fn main() {
                 Keyboard(Key::Right) => control_state.aim_right = false,
                 Keyboard(Key::Up) => control_state.aim_up = false,
                 Keyboard(Key::Down) => control_state.aim_down = false,
+                Keyboard(Key::Space) => control_state.jump = false,
                 _ => {}
             }
         });
 
-        // TODO where should this go?
-        for system in systems.iter_mut() {
-            // TODO need some kind of delta time
-            system.update(&control_state, &mut world.data, &mut world.entities);
+        let updated = states.current().updated_systems();
+
+        if updated != state::SYSTEMS_NONE {
+            // TODO where should this go?
+            for entry in systems.iter_mut() {
+                // TODO need some kind of delta time
+                if entry.0 & updated != 0 {
+                    entry.1.update(&control_state, &mut world.data, &mut world.entities);
+                }
+            }
+
+            control_state.jump_pressed = false;
+            control_state.next_weapon = false;
+            control_state.previous_weapon = false;
+
+            // Music triggers can change the level's music part way through
+            let track = level_music(&world);
+            if track != current_level_music {
+                current_level_music = track;
+                update_music(&mut music, states.current(), &world, &assets);
+            }
+
+            // Events posted by the systems can end the level or change the music volume
+            for entity in world.entities.iter() {
+                if let Some(receiver_id) = entity.event_receiver {
+                    for event in world.data.event_receiver.get(receiver_id).event_queue.iter() {
+                        if let world::Event::SetVolume(group, volume) = *event {
+                            music.set_volume(group, volume);
+                        }
+                        if transition == state::Transition::None {
+                            transition = states.current().event_transition(event);
+                        }
+                    }
+                }
+            }
+        }
+
+        // Pick up assets edited while the game is running
+        let now = time::precise_time_s();
+        if now - last_reload_check > 1.0 {
+            last_reload_check = now;
+            for name in assets.reload_changed(&mut world.data, &world.entities).iter() {
+                println!("Reloaded {}", name);
+            }
+        }
+
+        if transition != state::Transition::None {
+
+            let was_updated = states.current().updated_systems();
+
+            match transition {
+                state::Transition::Quit => break,
+                state::Transition::NewGame => {
+                    audio_backend.borrow_mut().stop_all();
+                    world = or_exit(load_world(&mut assets));
+                },
+                _ => {},
+            }
+
+            states.apply(transition);
+
+            control_state.jump_pressed = false;
+            control_state.next_weapon = false;
+            control_state.previous_weapon = false;
+
+            // Freeze or resume the systems the new state doesn't share with the old one
+            let updated = states.current().updated_systems();
+            for entry in systems.iter_mut() {
+                let was_updating = entry.0 & was_updated != 0;
+                let updating = entry.0 & updated != 0;
+                if updating != was_updating {
+                    entry.1.set_paused(!updating, &mut world.data, &mut world.entities);
+                }
+            }
+
+            update_music(&mut music, states.current(), &world, &assets);
         }
 
+        music.update(time::precise_time_s());
+
         if let Some(args) = e.render_args() {
 
             use graphics::*;
 
+            // Black bars around the virtual screen
             gl.draw([0, 0, args.width as i32, args.height as i32], |_, mut gl| {
-                graphics::clear([0.3, 0.3, 0.3, 1.0], gl);
+                graphics::clear([0.0, 0.0, 0.0, 1.0], gl);
+            });
+
+            let viewport = units::viewport_rect(args.width, args.height, units::SCALE_MODE);
+
+            let current_state = states.current();
+
+            gl.draw(viewport, |_, mut gl| {
+                // Clearing would wipe the whole window, so fill the viewport instead
+                let screen = Context::new();
+                graphics::rectangle([0.3, 0.3, 0.3, 1.0], [-1.0, -1.0, 2.0, 2.0], screen.transform, gl);
 
-                let context = get_camera_context(&world, args.width, args.height);
+                let ui_context = get_ui_context();
 
-                // TODO - probably want sprite sorting orders ..
-                // Currently just draw in order of creation ..
+                let rendered = current_state.rendered_systems();
 
-                for system in systems.iter_mut() {
-                    system.render(&context, gl, &mut world.data, &mut world.entities);
+                if rendered != state::SYSTEMS_NONE {
+                    let context = get_camera_context(&world);
+
+                    // TODO - probably want sprite sorting orders ..
+                    // Currently just draw in order of creation ..
+
+                    for entry in systems.iter_mut() {
+                        if entry.0 & rendered != 0 {
+                            entry.1.render(&context, gl, &mut world.data, &mut world.entities);
+                        }
+                    }
+
+                    // UI pass, drawn over the world without the camera transform
+                    for entry in systems.iter_mut() {
+                        if entry.0 & rendered != 0 {
+                            entry.1.render_ui(&ui_context, gl, &mut world.data, &mut world.entities);
+                        }
+                    }
                 }
+
+                current_state.render_overlay(&font, &ui_context, gl);
             });
         }
     }
@@ -0,0 +1,360 @@
+use std::num::Float;
+use vecmath::*;
+use id::Id;
+
+use audio::{AudioBackend, SoundId, VoiceId};
+use world;
+use units;
+
+/// Voices in the pool, sounds past this steal from lower priority ones
+pub const MAX_VOICES: usize = 16;
+
+/// Music volume before any `Event::SetVolume`, shared with the MusicPlayer
+pub const DEFAULT_MUSIC_VOLUME: f32 = 0.8;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum VolumeGroup {
+    Master,
+    Music,
+    Sfx,
+}
+
+///
+/// How a fire-and-forget sound is played
+///
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct SoundParams {
+    pub group: VolumeGroup,
+    /// Higher priority sounds can cut off lower ones when every voice is busy
+    pub priority: u8,
+    pub volume: f32,
+    pub looping: bool,
+    /// World position, None for sounds heard the same everywhere like music
+    pub position: Option<Vector2<f32>>,
+}
+
+impl SoundParams {
+
+    pub fn sfx(priority: u8) -> SoundParams {
+        SoundParams {
+            group: VolumeGroup::Sfx,
+            priority: priority,
+            volume: 1.0,
+            looping: false,
+            position: None,
+        }
+    }
+
+    /// Looping, and never stolen by sound effects
+    pub fn music() -> SoundParams {
+        SoundParams {
+            group: VolumeGroup::Music,
+            priority: 255,
+            volume: 1.0,
+            looping: true,
+            position: None,
+        }
+    }
+}
+
+///
+/// What the mixer last did with a voice. The backend only knows whether it's
+/// making sound, which is also false for paused voices that mustn't be reused.
+///
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum VoiceState {
+    Free,
+    /// Started, may have finished since
+    Playing,
+    Paused,
+}
+
+struct Channel {
+    voice: VoiceId,
+    state: VoiceState,
+    params: SoundParams,
+    /// Source that follows its entity around
+    owner: Option<Id<world::AudioSource>>,
+    /// Play order, to steal the oldest of equal priority sounds
+    sequence: u64,
+}
+
+impl Channel {
+
+    /// Whether the voice can be handed out without cutting anything off
+    fn is_free(&self, backend: &AudioBackend) -> bool {
+        match self.state {
+            VoiceState::Free => true,
+            VoiceState::Playing => !backend.is_playing(self.voice),
+            VoiceState::Paused => false,
+        }
+    }
+}
+
+///
+/// Plays sounds on a fixed pool of voices with master/music/sfx volumes.
+/// Positional sounds are attenuated and panned relative to the listener.
+///
+pub struct Mixer {
+    channels: Vec<Channel>,
+    sequence: u64,
+    pub master_volume: f32,
+    pub music_volume: f32,
+    pub sfx_volume: f32,
+
+    /// World position sounds are heard from
+    pub listener: Vector2<f32>,
+    /// Full volume within this distance, in world units
+    pub reference_distance: f32,
+    /// Silent beyond this distance, in world units
+    pub max_distance: f32,
+    /// Horizontal offset at which a sound is entirely in one speaker, in world units
+    pub pan_width: f32,
+}
+
+impl Mixer {
+
+    pub fn new() -> Mixer {
+        Mixer {
+            channels: Vec::with_capacity(MAX_VOICES),
+            sequence: 0,
+            master_volume: 1.0,
+            music_volume: DEFAULT_MUSIC_VOLUME,
+            sfx_volume: 1.0,
+
+            listener: [0.0, 0.0],
+            reference_distance: units::tiles(4.0),
+            max_distance: units::tiles(24.0),
+            pan_width: units::tiles(12.0),
+        }
+    }
+
+    fn group_volume(&self, group: VolumeGroup) -> f32 {
+        match group {
+            VolumeGroup::Master => 1.0,
+            VolumeGroup::Music => self.music_volume,
+            VolumeGroup::Sfx => self.sfx_volume,
+        }
+    }
+
+    fn gain(&self, params: &SoundParams) -> f32 {
+        let distance_gain = match params.position {
+            Some(position) => attenuation(vec2_len(vec2_sub(position, self.listener)), self.reference_distance, self.max_distance),
+            None => 1.0,
+        };
+        self.master_volume * self.group_volume(params.group) * params.volume * distance_gain
+    }
+
+    fn pan(&self, params: &SoundParams) -> f32 {
+        match params.position {
+            Some(position) => pan(position[0] - self.listener[0], self.pan_width),
+            None => 0.0,
+        }
+    }
+
+    fn apply(&self, backend: &mut AudioBackend, channel: &Channel) {
+        backend.set_gain(channel.voice, self.gain(&channel.params));
+        backend.set_pan(channel.voice, self.pan(&channel.params));
+    }
+
+    ///
+    /// Play a sound, returning the voice used, or None if every voice
+    /// is busy with something more important
+    ///
+    pub fn play(&mut self, backend: &mut AudioBackend, sound: SoundId, params: SoundParams, owner: Option<Id<world::AudioSource>>) -> Option<VoiceId> {
+
+        let free = self.channels.iter().position(|channel| channel.is_free(backend));
+
+        let index = match free {
+            Some(index) => index,
+            None if self.channels.len() < MAX_VOICES => {
+                let voice = backend.create_voice();
+                self.channels.push(Channel { voice: voice, state: VoiceState::Free, params: params, owner: owner, sequence: 0 });
+                self.channels.len() - 1
+            },
+            None => {
+                match steal_candidate(&self.channels, params.priority) {
+                    Some(index) => index,
+                    None => return None,
+                }
+            },
+        };
+
+        self.sequence += 1;
+
+        {
+            let channel = &mut self.channels[index];
+            channel.state = VoiceState::Playing;
+            channel.params = params;
+            channel.owner = owner;
+            channel.sequence = self.sequence;
+        }
+
+        let channel = &self.channels[index];
+        backend.play(channel.voice, sound);
+        backend.set_looping(channel.voice, params.looping);
+        self.apply(backend, channel);
+
+        Some(channel.voice)
+    }
+
+    ///
+    /// Move sounds still playing from an AudioSource along with its entity
+    ///
+    pub fn move_owner(&mut self, owner: Id<world::AudioSource>, position: Vector2<f32>) {
+        for channel in self.channels.iter_mut() {
+            if channel.owner == Some(owner) && channel.params.position.is_some() {
+                channel.params.position = Some(position);
+            }
+        }
+    }
+
+    ///
+    /// Re-apply attenuation and panning after the listener or sounds moved
+    ///
+    pub fn update_spatial(&self, backend: &mut AudioBackend) {
+        for channel in self.channels.iter() {
+            if channel.params.position.is_some() && backend.is_playing(channel.voice) {
+                self.apply(backend, channel);
+            }
+        }
+    }
+
+    ///
+    /// Change a group's volume, including sounds that are already playing
+    ///
+    pub fn set_volume(&mut self, backend: &mut AudioBackend, group: VolumeGroup, volume: f32) {
+
+        let volume = volume.max(0.0).min(1.0);
+
+        match group {
+            VolumeGroup::Master => self.master_volume = volume,
+            VolumeGroup::Music => self.music_volume = volume,
+            VolumeGroup::Sfx => self.sfx_volume = volume,
+        }
+
+        for channel in self.channels.iter() {
+            self.apply(backend, channel);
+        }
+    }
+
+    pub fn set_paused(&mut self, backend: &mut AudioBackend, paused: bool) {
+        for channel in self.channels.iter_mut() {
+            match (paused, channel.state) {
+                (true, VoiceState::Playing) => {
+                    backend.pause(channel.voice);
+                    channel.state = VoiceState::Paused;
+                },
+                (false, VoiceState::Paused) => {
+                    backend.resume(channel.voice);
+                    channel.state = VoiceState::Playing;
+                },
+                _ => {},
+            }
+        }
+    }
+}
+
+///
+/// The lowest priority channel not above `priority`, oldest first among equals
+///
+fn steal_candidate(channels: &[Channel], priority: u8) -> Option<usize> {
+
+    let mut candidate: Option<usize> = None;
+
+    for (index, channel) in channels.iter().enumerate() {
+        if channel.params.priority > priority {
+            continue;
+        }
+
+        candidate = match candidate {
+            Some(best) => {
+                let best_channel = &channels[best];
+                if channel.params.priority < best_channel.params.priority
+                    || (channel.params.priority == best_channel.params.priority && channel.sequence < best_channel.sequence) {
+                    Some(index)
+                } else {
+                    Some(best)
+                }
+            },
+            None => Some(index),
+        };
+    }
+
+    candidate
+}
+
+///
+/// Linear falloff, 1.0 within `reference` down to 0.0 at `max`
+///
+pub fn attenuation(distance: f32, reference: f32, max: f32) -> f32 {
+    if distance <= reference {
+        1.0
+    } else if distance >= max {
+        0.0
+    } else {
+        1.0 - (distance - reference) / (max - reference)
+    }
+}
+
+///
+/// Stereo position from -1.0 (left) to 1.0 (right) for a horizontal offset from the listener
+///
+pub fn pan(offset: f32, width: f32) -> f32 {
+    if width <= 0.0 {
+        return 0.0;
+    }
+    (offset / width).max(-1.0).min(1.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use audio::{SilentBackend, SoundId, VoiceId};
+    use super::{Channel, Mixer, SoundParams, VoiceState, steal_candidate};
+
+    fn channel(priority: u8, sequence: u64) -> Channel {
+        Channel {
+            voice: VoiceId(sequence as usize),
+            state: VoiceState::Playing,
+            params: SoundParams::sfx(priority),
+            owner: None,
+            sequence: sequence,
+        }
+    }
+
+    #[test]
+    fn steals_lowest_priority_first() {
+        let channels = [channel(100, 1), channel(50, 2), channel(200, 3)];
+        assert_eq!(steal_candidate(&channels, 150), Some(1));
+    }
+
+    #[test]
+    fn steals_oldest_of_equal_priority() {
+        let channels = [channel(100, 3), channel(100, 1), channel(100, 2)];
+        assert_eq!(steal_candidate(&channels, 100), Some(1));
+    }
+
+    #[test]
+    fn never_steals_higher_priority() {
+        let channels = [channel(100, 1), channel(255, 2)];
+        assert_eq!(steal_candidate(&channels, 99), None);
+        assert_eq!(steal_candidate(&channels, 255), Some(0));
+    }
+
+    #[test]
+    fn paused_voices_are_not_reused() {
+        let mut backend = SilentBackend::new();
+        let mut mixer = Mixer::new();
+
+        // Silent voices finish as soon as they start, so are free straight away
+        let first = mixer.play(&mut backend, SoundId(0), SoundParams::sfx(100), None).unwrap();
+        assert_eq!(mixer.play(&mut backend, SoundId(0), SoundParams::sfx(100), None), Some(first));
+
+        mixer.set_paused(&mut backend, true);
+        let second = mixer.play(&mut backend, SoundId(0), SoundParams::sfx(100), None).unwrap();
+        assert!(second != first);
+
+        mixer.set_paused(&mut backend, false);
+        assert_eq!(mixer.play(&mut backend, SoundId(0), SoundParams::sfx(100), None), Some(first));
+    }
+}
@@ -0,0 +1,277 @@
+use std::cmp;
+use std::num::Float;
+use std::path::Path;
+
+use audio::{self, SharedBackend, VoiceId};
+use mixer::{VolumeGroup, DEFAULT_MUSIC_VOLUME};
+use pcm::SoundStream;
+
+/// Buffers kept queued per stream, each one chunk long
+const STREAM_BUFFERS: usize = 4;
+/// Frames decoded per buffer, about a quarter second at 44.1kHz
+const CHUNK_FRAMES: usize = 11025;
+
+///
+/// A piece of music and the section of it that repeats
+///
+#[derive(Clone, Debug, PartialEq)]
+pub struct MusicTrack {
+    /// Asset name, e.g. "space_roaches.wav"
+    pub name: String,
+    /// Seconds to jump back to when the loop end is reached
+    pub loop_start: f32,
+    /// Seconds, the end of the file if not set
+    pub loop_end: Option<f32>,
+}
+
+impl MusicTrack {
+    pub fn new(name: &str) -> MusicTrack {
+        MusicTrack {
+            name: name.to_string(),
+            loop_start: 0.0,
+            loop_end: None,
+        }
+    }
+}
+
+///
+/// Linear volume ramp between two levels
+///
+#[derive(Copy, Clone, Debug)]
+struct Fade {
+    from: f32,
+    to: f32,
+    start: f64,
+    duration: f32,
+}
+
+impl Fade {
+    fn value(&self, now: f64) -> f32 {
+        fade_value(self.from, self.to, (now - self.start) as f32, self.duration)
+    }
+
+    fn finished(&self, now: f64) -> bool {
+        (now - self.start) as f32 >= self.duration
+    }
+}
+
+///
+/// Volume `elapsed` seconds into a fade from `from` to `to`
+///
+pub fn fade_value(from: f32, to: f32, elapsed: f32, duration: f32) -> f32 {
+    if duration <= 0.0 || elapsed >= duration {
+        to
+    } else {
+        from + (to - from) * (elapsed / duration).max(0.0)
+    }
+}
+
+struct Stream {
+    track: MusicTrack,
+    decoder: SoundStream,
+    voice: VoiceId,
+    /// Loop points in frames
+    loop_start: u64,
+    loop_end: u64,
+    /// Next frame to decode
+    position: u64,
+    fade: Fade,
+}
+
+impl Stream {
+
+    ///
+    /// Top up the voice's queue, jumping back to the loop start at the loop end
+    ///
+    fn refill(&mut self, backend: &mut audio::AudioBackend, samples: &mut Vec<i16>) {
+
+        let mut queued = backend.unqueue_processed(self.voice);
+
+        while queued < STREAM_BUFFERS {
+
+            if self.position >= self.loop_end {
+                if self.decoder.seek(self.loop_start).is_err() {
+                    return;
+                }
+                self.position = self.loop_start;
+            }
+
+            let frames = cmp::min(CHUNK_FRAMES as u64, self.loop_end - self.position) as usize;
+
+            samples.clear();
+            let read = self.decoder.read(frames, samples);
+
+            if read == 0 {
+                // File is shorter than it claimed, treat this as the loop end
+                if self.position == self.loop_start {
+                    return;
+                }
+                self.loop_end = self.position;
+                continue;
+            }
+
+            self.position += read as u64;
+            backend.queue_samples(self.voice, &samples[..], self.decoder.channels, self.decoder.sample_rate);
+            queued += 1;
+        }
+    }
+}
+
+///
+/// Streams music from disk a chunk at a time, looping between loop points
+/// and crossfading between tracks. Lives outside the world so music keeps
+/// going across state changes and level reloads.
+///
+pub struct MusicPlayer {
+    backend: SharedBackend,
+    current: Option<Stream>,
+    /// Previous tracks still fading out
+    fading: Vec<Stream>,
+    free_voices: Vec<VoiceId>,
+    /// Reused between refills so streaming doesn't allocate
+    samples: Vec<i16>,
+    /// Kept in step with the mixer's volumes by `set_volume`
+    master_volume: f32,
+    music_volume: f32,
+    paused: bool,
+}
+
+impl MusicPlayer {
+
+    pub fn new(backend: SharedBackend) -> MusicPlayer {
+        MusicPlayer {
+            backend: backend,
+            current: None,
+            fading: Vec::new(),
+            free_voices: Vec::new(),
+            samples: Vec::with_capacity(CHUNK_FRAMES * 2),
+            master_volume: 1.0,
+            music_volume: DEFAULT_MUSIC_VOLUME,
+            paused: false,
+        }
+    }
+
+    pub fn current_track(&self) -> Option<&MusicTrack> {
+        self.current.as_ref().map(|stream| &stream.track)
+    }
+
+    ///
+    /// Crossfade to a track over `fade` seconds, nothing happens if it's already playing.
+    /// Missing or broken music is reported but isn't fatal.
+    ///
+    pub fn play(&mut self, track: &MusicTrack, path: &Path, fade: f32, now: f64) {
+
+        if self.current_track() == Some(track) {
+            return;
+        }
+
+        let decoder = match SoundStream::open(&path.to_string_lossy()[..]) {
+            Ok(decoder) => decoder,
+            Err(e) => {
+                println!("Warning: can't play music, {}", e);
+                return;
+            }
+        };
+
+        self.stop(fade, now);
+
+        let voice = match self.free_voices.pop() {
+            Some(voice) => voice,
+            None => self.backend.borrow_mut().create_voice(),
+        };
+
+        let rate = decoder.sample_rate as f32;
+        let loop_end = match track.loop_end {
+            Some(seconds) => cmp::min((seconds * rate) as u64, decoder.frames),
+            None => decoder.frames,
+        };
+        let loop_start = cmp::min((track.loop_start * rate) as u64, loop_end);
+
+        self.current = Some(Stream {
+            track: track.clone(),
+            decoder: decoder,
+            voice: voice,
+            loop_start: loop_start,
+            loop_end: loop_end,
+            position: 0,
+            fade: Fade { from: 0.0, to: 1.0, start: now, duration: fade },
+        });
+    }
+
+    ///
+    /// Fade out whatever is playing over `fade` seconds
+    ///
+    pub fn stop(&mut self, fade: f32, now: f64) {
+        if let Some(mut stream) = self.current.take() {
+            let volume = stream.fade.value(now);
+            stream.fade = Fade { from: volume, to: 0.0, start: now, duration: fade * volume };
+            self.fading.push(stream);
+        }
+    }
+
+    ///
+    /// Same as the mixer's `set_volume`, music lives outside the world so
+    /// `Event::SetVolume` has to be passed on to both
+    ///
+    pub fn set_volume(&mut self, group: VolumeGroup, volume: f32) {
+
+        let volume = volume.max(0.0).min(1.0);
+
+        match group {
+            VolumeGroup::Master => self.master_volume = volume,
+            VolumeGroup::Music => self.music_volume = volume,
+            VolumeGroup::Sfx => {},
+        }
+    }
+
+    pub fn set_paused(&mut self, paused: bool) {
+
+        self.paused = paused;
+
+        let mut backend = self.backend.borrow_mut();
+        for stream in self.current.iter().chain(self.fading.iter()) {
+            if paused {
+                backend.pause(stream.voice);
+            } else {
+                backend.resume(stream.voice);
+            }
+        }
+    }
+
+    ///
+    /// Keep the streams fed and apply fades, call every frame
+    ///
+    pub fn update(&mut self, now: f64) {
+
+        if self.paused {
+            return;
+        }
+
+        let mut backend = self.backend.borrow_mut();
+        let volume = self.master_volume * self.music_volume;
+
+        // Drop tracks that have faded out, freeing their voices
+        let mut i = 0;
+        while i < self.fading.len() {
+            if self.fading[i].fade.finished(now) {
+                let stream = self.fading.swap_remove(i);
+                backend.clear_queue(stream.voice);
+                self.free_voices.push(stream.voice);
+            } else {
+                i += 1;
+            }
+        }
+
+        if let Some(stream) = self.current.as_mut() {
+            stream.refill(&mut **backend, &mut self.samples);
+            backend.set_gain(stream.voice, volume * stream.fade.value(now));
+            backend.play_queued(stream.voice);
+        }
+
+        for stream in self.fading.iter_mut() {
+            stream.refill(&mut **backend, &mut self.samples);
+            backend.set_gain(stream.voice, volume * stream.fade.value(now));
+            backend.play_queued(stream.voice);
+        }
+    }
+}
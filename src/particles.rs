@@ -0,0 +1,340 @@
+use graphics::Context;
+use opengl_graphics::GlGraphics;
+use vecmath::*;
+use std::num::Float;
+use std::cmp;
+use std::rc::Rc;
+
+use assets::AssetManager;
+use error;
+use world;
+use time;
+use units;
+
+/// Upper bound on live particles, storage is allocated once up front
+pub const MAX_PARTICLES: usize = 1024;
+
+impl world::ParticleEmitter {
+
+    pub fn new(effect: Rc<world::ParticleEffect>, rate: f32) -> world::ParticleEmitter {
+        world::ParticleEmitter {
+            effect: effect,
+            rate: rate,
+            direction: [0.0, -1.0],
+            active: true,
+            accumulator: 0.0,
+        }
+    }
+}
+
+struct Particle {
+    effect: Rc<world::ParticleEffect>,
+    position: Vector2<f32>,
+    velocity: Vector2<f32>,
+    age: f32,
+    lifetime: f32,
+}
+
+impl Particle {
+
+    fn life_fraction(&self) -> f32 {
+        (self.age / self.lifetime).min(1.0)
+    }
+}
+
+///
+/// Small xorshift generator, good enough for scattering particles
+///
+struct Rng {
+    state: u32,
+}
+
+impl Rng {
+
+    fn new(seed: u32) -> Rng {
+        Rng { state: if seed == 0 { 0x9E3779B9 } else { seed } }
+    }
+
+    fn next(&mut self) -> u32 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 17;
+        self.state ^= self.state << 5;
+        self.state
+    }
+
+    /// Uniform in [range[0], range[1]]
+    fn range(&mut self, range: [f32; 2]) -> f32 {
+        let t = self.next() as f32 / 4294967295.0;
+        range[0] + (range[1] - range[0]) * t
+    }
+}
+
+pub fn lerp(a: f32, b: f32, t: f32) -> f32 {
+    a + (b - a) * t
+}
+
+pub fn lerp_color(a: [f32; 4], b: [f32; 4], t: f32) -> [f32; 4] {
+    [lerp(a[0], b[0], t), lerp(a[1], b[1], t), lerp(a[2], b[2], t), lerp(a[3], b[3], t)]
+}
+
+fn muzzle_flash_effect() -> world::ParticleEffect {
+    world::ParticleEffect {
+        count: 6,
+        lifetime: [0.04, 0.1],
+        speed: [units::tiles(2.0), units::tiles(6.0)],
+        spread: 0.4,
+        gravity: [0.0, 0.0],
+        size: [units::tiles(0.2), units::tiles(0.05)],
+        start_color: [1.0, 0.95, 0.6, 1.0],
+        end_color: [1.0, 0.4, 0.1, 0.0],
+        texture: None,
+        frames: Vec::new(),
+    }
+}
+
+fn impact_effect(assets: &mut AssetManager) -> error::Result<world::ParticleEffect> {
+    let handle = try!(assets.load_texture("Bullet.png"));
+    let texture = assets.shared_texture(handle);
+    Ok(world::ParticleEffect {
+        count: 10,
+        lifetime: [0.15, 0.35],
+        speed: [units::tiles(1.0), units::tiles(5.0)],
+        spread: 1.2,
+        gravity: [0.0, units::tiles(20.0)],
+        size: [units::tiles(0.15), units::tiles(0.05)],
+        start_color: [1.0, 1.0, 1.0, 1.0],
+        end_color: [0.6, 0.6, 0.6, 0.0],
+        texture: Some(texture),
+        frames: vec![[0, 0, 8, 8]],
+    })
+}
+
+fn gibs_effect() -> world::ParticleEffect {
+    world::ParticleEffect {
+        count: 24,
+        lifetime: [0.4, 0.9],
+        speed: [units::tiles(2.0), units::tiles(8.0)],
+        spread: 3.14159,
+        gravity: [0.0, units::tiles(25.0)],
+        size: [units::tiles(0.2), units::tiles(0.1)],
+        start_color: [0.45, 0.3, 0.1, 1.0],
+        end_color: [0.3, 0.5, 0.1, 0.0],
+        texture: None,
+        frames: Vec::new(),
+    }
+}
+
+fn debris_effect(assets: &mut AssetManager) -> error::Result<world::ParticleEffect> {
+    let handle = try!(assets.load_texture("Block.png"));
+    let texture = assets.shared_texture(handle);
+    Ok(world::ParticleEffect {
+        count: 16,
+        lifetime: [0.5, 1.0],
+        speed: [units::tiles(1.0), units::tiles(6.0)],
+        spread: 3.14159,
+        gravity: [0.0, units::tiles(30.0)],
+        size: [units::tiles(0.3), units::tiles(0.15)],
+        start_color: [1.0, 1.0, 1.0, 1.0],
+        end_color: [0.5, 0.5, 0.5, 0.0],
+        texture: Some(texture),
+        frames: vec![[0, 0, 10, 10], [12, 4, 8, 8], [4, 14, 6, 6]],
+    })
+}
+
+pub struct ParticleSystem {
+    particles: Vec<Particle>,
+    rng: Rng,
+    last_update: f64,
+
+    muzzle_flash: Rc<world::ParticleEffect>,
+    impact: Rc<world::ParticleEffect>,
+    gibs: Rc<world::ParticleEffect>,
+    debris: Rc<world::ParticleEffect>,
+}
+
+impl ParticleSystem {
+
+    pub fn new(assets: &mut AssetManager) -> error::Result<ParticleSystem> {
+        Ok(ParticleSystem {
+            particles: Vec::with_capacity(MAX_PARTICLES),
+            rng: Rng::new(time::precise_time_s() as u32),
+            last_update: time::precise_time_s(),
+            muzzle_flash: Rc::new(muzzle_flash_effect()),
+            impact: Rc::new(try!(impact_effect(assets))),
+            gibs: Rc::new(gibs_effect()),
+            debris: Rc::new(try!(debris_effect(assets))),
+        })
+    }
+
+    ///
+    /// Spawn a single particle, dropped if the pool is full
+    ///
+    fn spawn(&mut self, effect: &Rc<world::ParticleEffect>, position: Vector2<f32>, direction: Vector2<f32>) {
+
+        if self.particles.len() >= MAX_PARTICLES {
+            return;
+        }
+
+        let base_angle = direction[1].atan2(direction[0]);
+        let angle = base_angle + self.rng.range([-effect.spread, effect.spread]);
+        let speed = self.rng.range(effect.speed);
+        let lifetime = self.rng.range(effect.lifetime);
+
+        self.particles.push(Particle {
+            effect: effect.clone(),
+            position: position,
+            velocity: [angle.cos() * speed, angle.sin() * speed],
+            age: 0.0,
+            lifetime: lifetime,
+        });
+    }
+
+    ///
+    /// Spawn `effect.count` particles at once
+    ///
+    pub fn burst(&mut self, effect: &Rc<world::ParticleEffect>, position: Vector2<f32>, direction: Vector2<f32>) {
+        for _ in 0..effect.count {
+            self.spawn(effect, position, direction);
+        }
+    }
+
+    fn simulate(&mut self, dt: f32) {
+        let mut i = 0;
+        while i < self.particles.len() {
+            let dead = {
+                let particle = &mut self.particles[i];
+                particle.age += dt;
+                particle.velocity = vec2_add(particle.velocity, vec2_scale(particle.effect.gravity, dt));
+                particle.position = vec2_add(particle.position, vec2_scale(particle.velocity, dt));
+                particle.age >= particle.lifetime
+            };
+
+            if dead {
+                // Order doesn't matter, so avoid shifting the rest down
+                self.particles.swap_remove(i);
+            } else {
+                i += 1;
+            }
+        }
+    }
+}
+
+impl world::System for ParticleSystem {
+
+    fn update(&mut self, control_state: &world::ControlState, components: &mut world::Components, entities: &mut Vec<world::Entity>) {
+
+        let now = time::precise_time_s();
+        let dt = (now - self.last_update) as f32;
+        self.last_update = now;
+
+        // Bursts triggered by events
+        for entity in entities.iter() {
+            if let Some(receiver_id) = entity.event_receiver {
+                let receiver = components.event_receiver.get(receiver_id);
+
+                for event in receiver.event_queue.iter() {
+                    match *event {
+                        world::Event::WeaponFired(origin, direction) => {
+                            let effect = self.muzzle_flash.clone();
+                            self.burst(&effect, origin, direction);
+                        },
+                        world::Event::Collision(_, _) => {
+                            if let (Some(_), Some(position_id)) = (entity.bullet, entity.position) {
+                                let position = components.position.get(position_id);
+
+                                // Spray back the way the bullet came
+                                let mut direction = [0.0, -1.0];
+                                if let Some(body_id) = entity.dynamic_body {
+                                    let body = components.dynamic_body.get(body_id);
+                                    if body.vx != 0.0 || body.vy != 0.0 {
+                                        direction = vec2_normalized([-body.vx, -body.vy]);
+                                    }
+                                }
+
+                                let effect = self.impact.clone();
+                                self.burst(&effect, [position.x, position.y], direction);
+                            }
+                        },
+                        world::Event::Died => {
+                            if let (Some(_), Some(position_id)) = (entity.enemy, entity.position) {
+                                let position = components.position.get(position_id);
+                                let effect = self.gibs.clone();
+                                self.burst(&effect, [position.x, position.y], [0.0, -1.0]);
+                            }
+                            if let (Some(_), Some(position_id)) = (entity.hive, entity.position) {
+                                let position = components.position.get(position_id);
+                                let effect = self.gibs.clone();
+                                self.burst(&effect, [position.x, position.y], [0.0, -1.0]);
+                            }
+                            if let (Some(_), Some(position_id)) = (entity.destructible, entity.position) {
+                                let position = components.position.get(position_id);
+                                let effect = self.debris.clone();
+                                self.burst(&effect, [position.x, position.y], [0.0, -1.0]);
+                            }
+                        },
+                        _ => {},
+                    }
+                }
+            }
+        }
+
+        // Continuous emitters
+        for entity in entities.iter() {
+            if let (Some(emitter_id), Some(position_id)) = (entity.particle_emitter, entity.position) {
+                let emitter = components.particle_emitter.get_mut(emitter_id);
+                let position = components.position.get(position_id);
+
+                if !emitter.active {
+                    emitter.accumulator = 0.0;
+                    continue;
+                }
+
+                emitter.accumulator += emitter.rate * dt;
+                while emitter.accumulator >= 1.0 {
+                    emitter.accumulator -= 1.0;
+                    self.spawn(&emitter.effect, [position.x, position.y], emitter.direction);
+                }
+            }
+        }
+
+        self.simulate(dt);
+    }
+
+    fn set_paused(&mut self, paused: bool, components: &mut world::Components, entities: &mut Vec<world::Entity>) {
+        if !paused {
+            self.last_update = time::precise_time_s();
+        }
+    }
+
+    fn render(&mut self, context: &Context, gl: &mut GlGraphics, components: &mut world::Components, entities: &mut Vec<world::Entity>) {
+
+        use graphics::*;
+
+        for particle in self.particles.iter() {
+            let effect = &particle.effect;
+            let t = particle.life_fraction();
+
+            let color = lerp_color(effect.start_color, effect.end_color, t);
+            let size = lerp(effect.size[0], effect.size[1], t) as f64;
+            let rect = [
+                particle.position[0] as f64 - size / 2.0,
+                particle.position[1] as f64 - size / 2.0,
+                size,
+                size,
+            ];
+
+            match effect.texture {
+                Some(ref texture) if effect.frames.len() > 0 => {
+                    let frame_index = cmp::min((t * effect.frames.len() as f32) as usize, effect.frames.len() - 1);
+                    Image::colored(color)
+                        .src_rect(effect.frames[frame_index])
+                        .rect(rect)
+                        .draw(&*texture.get(), &context.draw_state, context.transform, gl);
+                },
+                _ => {
+                    rectangle(color, rect, context.transform, gl);
+                },
+            }
+        }
+    }
+}
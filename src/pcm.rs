@@ -0,0 +1,362 @@
+///
+/// Decoding and converting sound files, independent of any audio device.
+/// libsndfile does the decoding, so Ogg Vorbis needs it built with libvorbis.
+///
+use std::ascii::AsciiExt;
+use std::cmp;
+use std::path::Path;
+use std::result;
+use sndfile::{SndFile, OpenMode, SeekMode};
+
+use error::{Error, Result};
+
+const READ_BUFFER_SIZE: usize = 4096;
+
+/// Extensions the loader accepts
+pub const SUPPORTED_EXTENSIONS: &'static [&'static str] = &["wav", "ogg", "oga", "flac", "aif", "aiff"];
+
+/// Sample rate sounds are converted to when loaded whole
+pub const OUTPUT_SAMPLE_RATE: u32 = 44100;
+
+///
+/// Decoded 16 bit PCM samples, interleaved if there's more than one channel
+///
+pub struct SoundData {
+    pub samples: Vec<i16>,
+    pub channels: u32,
+    pub sample_rate: u32,
+}
+
+impl SoundData {
+    pub fn duration(&self) -> f32 {
+        self.samples.len() as f32 / (self.channels * self.sample_rate) as f32
+    }
+}
+
+///
+/// What decoded sounds are converted to, None keeps the file's own value
+///
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct SoundFormat {
+    pub channels: Option<u32>,
+    pub sample_rate: Option<u32>,
+}
+
+impl SoundFormat {
+
+    /// Mono so effects can be panned, at the output rate
+    pub fn effect() -> SoundFormat {
+        SoundFormat {
+            channels: Some(1),
+            sample_rate: Some(OUTPUT_SAMPLE_RATE),
+        }
+    }
+}
+
+///
+/// Sound file opened for reading a chunk at a time, for music too long to decode up front
+///
+pub struct SoundStream {
+    file: SndFile,
+    path: String,
+    pub channels: u32,
+    pub sample_rate: u32,
+    /// Length in frames, one sample per channel
+    pub frames: u64,
+}
+
+impl SoundStream {
+
+    pub fn open(path: &str) -> Result<SoundStream> {
+
+        try!(check_extension(path));
+
+        let mut file = try!(SndFile::new(path, OpenMode::Read).map_err(|e| Error::Sound(path.to_string(), e)));
+        let sndinfo = file.get_sndinfo();
+
+        try!(check_channels(path, sndinfo.channels as u32));
+
+        Ok(SoundStream {
+            file: file,
+            path: path.to_string(),
+            channels: sndinfo.channels as u32,
+            sample_rate: sndinfo.samplerate as u32,
+            frames: sndinfo.frames as u64,
+        })
+    }
+
+    ///
+    /// Append up to `frames` frames to `samples`, returning how many were read,
+    /// 0 at the end of the file
+    ///
+    pub fn read(&mut self, frames: usize, samples: &mut Vec<i16>) -> usize {
+
+        let mut read_buf: [i16; READ_BUFFER_SIZE] = [0; READ_BUFFER_SIZE];
+        let channels = self.channels as usize;
+        let mut remaining = frames * channels;
+
+        while remaining > 0 {
+            let request = cmp::min(remaining, READ_BUFFER_SIZE - READ_BUFFER_SIZE % channels);
+            let samples_read = self.file.read_i16(&mut read_buf[..], request as i64) as usize;
+            if samples_read == 0 { break; }
+            samples.push_all(&read_buf[..samples_read]);
+            remaining -= samples_read;
+        }
+
+        (frames * channels - remaining) / channels
+    }
+
+    pub fn seek(&mut self, frame: u64) -> Result<()> {
+        if self.file.seek(frame as i64, SeekMode::SeekSet) < 0 {
+            return Err(Error::Sound(self.path.clone(), format!("couldn't seek to frame {}", frame)));
+        }
+        Ok(())
+    }
+}
+
+fn check_extension(path: &str) -> Result<()> {
+
+    let extension = Path::new(path).extension()
+        .and_then(|extension| extension.to_str())
+        .map(|extension| extension.to_ascii_lowercase());
+
+    match extension {
+        Some(ref extension) if SUPPORTED_EXTENSIONS.contains(&&extension[..]) => Ok(()),
+        Some(extension) => Err(Error::Sound(path.to_string(), format!("unsupported format '.{}'", extension))),
+        None => Err(Error::Sound(path.to_string(), "no file extension to tell the format from".to_string())),
+    }
+}
+
+///
+/// OpenAL only takes mono and stereo, and there's no sensible downmix for unknown layouts
+///
+pub fn check_channels(path: &str, channels: u32) -> Result<()> {
+    if channels == 1 || channels == 2 {
+        Ok(())
+    } else {
+        Err(Error::Sound(path.to_string(), format!("{} channels, only mono and stereo are supported", channels)))
+    }
+}
+
+///
+/// Decode a whole sound file into 16 bit samples
+///
+pub fn decode(path: &str) -> Result<SoundData> {
+
+    let mut stream = try!(SoundStream::open(path));
+
+    let mut samples: Vec<i16> = Vec::with_capacity((stream.frames * stream.channels as u64) as usize);
+    while stream.read(READ_BUFFER_SIZE, &mut samples) > 0 {}
+
+    Ok(SoundData {
+        samples: samples,
+        channels: stream.channels,
+        sample_rate: stream.sample_rate,
+    })
+}
+
+///
+/// Decode a whole sound file and convert it to `format`
+///
+pub fn decode_as(path: &str, format: SoundFormat) -> Result<SoundData> {
+    let data = try!(decode(path));
+    convert(data, format).map_err(|e| Error::Sound(path.to_string(), e))
+}
+
+///
+/// Downmix, upmix and resample to `format`
+///
+pub fn convert(data: SoundData, format: SoundFormat) -> result::Result<SoundData, String> {
+
+    let mut data = data;
+
+    match format.channels {
+        Some(1) if data.channels == 2 => {
+            data.samples = downmix(&data.samples[..]);
+            data.channels = 1;
+        },
+        Some(2) if data.channels == 1 => {
+            data.samples = upmix(&data.samples[..]);
+            data.channels = 2;
+        },
+        Some(channels) if channels != data.channels => {
+            return Err(format!("can't convert {} channels to {}", data.channels, channels));
+        },
+        _ => {},
+    }
+
+    if let Some(rate) = format.sample_rate {
+        if rate != data.sample_rate {
+            data.samples = resample(&data.samples[..], data.channels, data.sample_rate, rate);
+            data.sample_rate = rate;
+        }
+    }
+
+    Ok(data)
+}
+
+///
+/// Average interleaved stereo down to mono
+///
+pub fn downmix(samples: &[i16]) -> Vec<i16> {
+    samples.chunks(2)
+        .map(|frame| {
+            let sum = frame.iter().fold(0i32, |sum, &sample| sum + sample as i32);
+            (sum / frame.len() as i32) as i16
+        })
+        .collect()
+}
+
+///
+/// Duplicate mono into both stereo channels
+///
+pub fn upmix(samples: &[i16]) -> Vec<i16> {
+    let mut stereo = Vec::with_capacity(samples.len() * 2);
+    for &sample in samples.iter() {
+        stereo.push(sample);
+        stereo.push(sample);
+    }
+    stereo
+}
+
+///
+/// Linear interpolation between frames, good enough for sound effects
+///
+pub fn resample(samples: &[i16], channels: u32, from_rate: u32, to_rate: u32) -> Vec<i16> {
+
+    let channels = channels as usize;
+    let frames = samples.len() / channels;
+
+    if frames == 0 || from_rate == to_rate {
+        return samples.to_vec();
+    }
+
+    let out_frames = (frames as u64 * to_rate as u64 / from_rate as u64) as usize;
+    let step = from_rate as f64 / to_rate as f64;
+
+    let mut out = Vec::with_capacity(out_frames * channels);
+
+    for i in 0..out_frames {
+        let position = i as f64 * step;
+        let frame = position as usize;
+        let next = cmp::min(frame + 1, frames - 1);
+        let t = position - frame as f64;
+
+        for channel in 0..channels {
+            let a = samples[frame * channels + channel] as f64;
+            let b = samples[next * channels + channel] as f64;
+            out.push((a + (b - a) * t) as i16);
+        }
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use error::Error;
+    use super::{check_channels, decode, decode_as, convert, downmix, upmix, resample, SoundFormat};
+
+    fn fixture(name: &str) -> String {
+        format!("tests/fixtures/{}", name)
+    }
+
+    /// Samples in ramp_mono_22050.wav, the left channel of ramp_stereo_22050.wav
+    fn ramp() -> Vec<i16> {
+        (0..64).map(|i| i * 100).collect()
+    }
+
+    #[test]
+    fn decodes_wav() {
+        let data = decode(&fixture("ramp_mono_22050.wav")).unwrap();
+        assert_eq!(data.channels, 1);
+        assert_eq!(data.sample_rate, 22050);
+        assert_eq!(data.samples, ramp());
+    }
+
+    #[test]
+    fn decodes_ogg() {
+        let data = decode(&fixture("silence_mono_22050.ogg")).unwrap();
+        assert_eq!(data.channels, 1);
+        assert_eq!(data.sample_rate, 22050);
+        assert_eq!(data.samples.len(), 4096);
+        assert!(data.samples.iter().all(|&sample| sample == 0));
+    }
+
+    #[test]
+    fn downmix_averages_stereo() {
+        assert_eq!(downmix(&[100, 300, -50, 50, 7, 7]), vec![200, 0, 7]);
+
+        let data = decode(&fixture("ramp_stereo_22050.wav")).unwrap();
+        assert_eq!(data.channels, 2);
+
+        let mono = convert(data, SoundFormat { channels: Some(1), sample_rate: None }).unwrap();
+        assert_eq!(mono.channels, 1);
+        assert_eq!(mono.samples, ramp().iter().map(|&sample| sample + 100).collect::<Vec<i16>>());
+    }
+
+    #[test]
+    fn upmix_duplicates_mono() {
+        assert_eq!(upmix(&[1, -2, 3]), vec![1, 1, -2, -2, 3, 3]);
+
+        let data = decode(&fixture("ramp_mono_22050.wav")).unwrap();
+        let stereo = convert(data, SoundFormat { channels: Some(2), sample_rate: None }).unwrap();
+        assert_eq!(stereo.channels, 2);
+        assert_eq!(stereo.samples.len(), 128);
+        assert_eq!(&stereo.samples[..6], &[0, 0, 100, 100, 200, 200]);
+    }
+
+    #[test]
+    fn resample_doubles_rate() {
+        let input = ramp();
+        let output = resample(&input[..], 1, 22050, 44100);
+        assert_eq!(output.len(), input.len() * 2);
+
+        // Input frames land on even outputs, odd ones are halfway to the next,
+        // and the last frame is held rather than read past the end
+        for (i, &sample) in output.iter().enumerate() {
+            let expected = if i == output.len() - 1 { 6300 } else { i as i16 * 50 };
+            assert_eq!(sample, expected);
+        }
+    }
+
+    #[test]
+    fn resample_keeps_channels_apart() {
+        let output = resample(&[0, 1000, 100, 2000], 2, 22050, 44100);
+        assert_eq!(output, vec![0, 1000, 50, 1500, 100, 2000, 100, 2000]);
+    }
+
+    #[test]
+    fn decode_as_converts_effects() {
+        let data = decode_as(&fixture("ramp_stereo_22050.wav"), SoundFormat::effect()).unwrap();
+        assert_eq!(data.channels, 1);
+        assert_eq!(data.sample_rate, 44100);
+        assert_eq!(data.samples.len(), 128);
+        assert_eq!(&data.samples[..4], &[100, 150, 200, 250]);
+    }
+
+    #[test]
+    fn rejects_more_than_two_channels() {
+        assert!(check_channels("mono.wav", 1).is_ok());
+        assert!(check_channels("stereo.wav", 2).is_ok());
+        assert!(check_channels("surround.wav", 6).is_err());
+
+        match decode(&fixture("three_channels.wav")) {
+            Err(Error::Sound(_, message)) => assert!(message.contains("3 channels")),
+            _ => panic!("three channel file should be rejected"),
+        }
+    }
+
+    #[test]
+    fn rejects_unsupported_extensions() {
+        match decode(&fixture("ramp_mono_22050.mp3")) {
+            Err(Error::Sound(_, message)) => assert!(message.contains("unsupported format '.mp3'")),
+            _ => panic!("mp3 should be rejected"),
+        }
+
+        match decode(&fixture("ramp_mono_22050")) {
+            Err(Error::Sound(_, message)) => assert!(message.contains("no file extension")),
+            _ => panic!("file without an extension should be rejected"),
+        }
+    }
+}
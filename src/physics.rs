@@ -1,45 +1,208 @@
 use graphics::Context;
 use opengl_graphics::GlGraphics;
+use vecmath::*;
+use std::num::Float;
+
+use id::Id;
 
 use world;
+use units;
 use event::*;
 
+/// Collision layers, a collider is on one and has a mask of the ones it collides with
+pub const LAYER_STATIC: u32 = 1 << 0;
+pub const LAYER_PLAYER: u32 = 1 << 1;
+pub const LAYER_ENEMY: u32 = 1 << 2;
+pub const LAYER_PLAYER_PROJECTILE: u32 = 1 << 3;
+pub const LAYER_ENEMY_PROJECTILE: u32 = 1 << 4;
+pub const LAYER_PICKUP: u32 = 1 << 5;
+pub const LAYER_TRIGGER: u32 = 1 << 6;
+pub const ALL_LAYERS: u32 = !0;
+
+/// Pull on dynamic bodies with a gravity scale, world units per update per update
+pub const GRAVITY: [f32; 2] = [0.0, units::UNITS_PER_TILE / 100.0];
+
+impl world::DynamicBody {
+
+    ///
+    /// Dynamic body of mass 1 that falls, doesn't bounce and isn't slowed down
+    ///
+    pub fn new(vx: f32, vy: f32) -> world::DynamicBody {
+        world::DynamicBody {
+            vx: vx,
+            vy: vy,
+            mass: 1.0,
+            restitution: 0.0,
+            friction: 0.0,
+            drag: 0.0,
+            gravity_scale: 1.0,
+            max_speed: None,
+            kinematic: false,
+            ground: None,
+        }
+    }
+
+    ///
+    /// Kinematic bodies can't be pushed around, as if they were infinitely heavy
+    ///
+    pub fn inverse_mass(&self) -> f32 {
+        if self.kinematic || self.mass <= 0.0 { 0.0 } else { 1.0 / self.mass }
+    }
+
+    ///
+    /// Instantaneous change in momentum
+    ///
+    pub fn apply_impulse(&mut self, impulse: Vector2<f32>) {
+        let inverse_mass = self.inverse_mass();
+        self.vx += impulse[0] * inverse_mass;
+        self.vy += impulse[1] * inverse_mass;
+    }
+}
+
+impl world::PathFollower {
+
+    pub fn new(points: Vec<Vector2<f32>>, speed: f32, looping: bool) -> world::PathFollower {
+        world::PathFollower {
+            points: points,
+            speed: speed,
+            looping: looping,
+            target: 0,
+            forward: true,
+        }
+    }
+
+    ///
+    /// Velocity to move from `position` towards the next point this update,
+    /// moving on to the point after once it's reached
+    ///
+    pub fn velocity(&mut self, position: Vector2<f32>) -> Vector2<f32> {
+
+        if self.points.len() == 0 {
+            return [0.0, 0.0];
+        }
+
+        let offset = vec2_sub(self.points[self.target], position);
+        let distance = vec2_len(offset);
+
+        if distance > self.speed {
+            return vec2_scale(offset, self.speed / distance);
+        }
+
+        self.advance();
+        offset
+    }
+
+    fn advance(&mut self) {
+
+        let last = self.points.len() - 1;
+        if last == 0 {
+            return;
+        }
+
+        if self.forward {
+            if self.target < last {
+                self.target += 1;
+            } else if self.looping {
+                self.target = 0;
+            } else {
+                self.forward = false;
+                self.target -= 1;
+            }
+        } else {
+            if self.target > 0 {
+                self.target -= 1;
+            } else {
+                self.forward = true;
+                self.target = 1;
+            }
+        }
+    }
+}
+
 pub struct PhysicsSystem;
 
 impl world::System for PhysicsSystem {
 
     fn update(&mut self, control_state: &world::ControlState, components: &mut world::Components, entities: &mut Vec<world::Entity>) {
 
+        // Platforms go first so whatever they carry or push can still move this update
+        for entity in entities.iter() {
+            if let (Some(_), Some(_), Some(_), Some(_)) = (entity.path_follower, entity.dynamic_body, entity.collider, entity.position) {
+                move_platform(entity, components, entities);
+            }
+        }
+
         // For each dynamic entity with a collider...
         for entity in entities.iter() {
             if let (Some(body_id), Some(collider_id), Some(position_id)) = (entity.dynamic_body, entity.collider, entity.position) {
 
-                let body = components.dynamic_body.get(body_id);
-                let collider = components.collider.get(collider_id);
+                if entity.path_follower.is_some() {
+                    continue;
+                }
 
-                let mut new_position = components.position.get(position_id).clone();
-                new_position.x += body.vx;
-                new_position.y += body.vy;
+                let collider = *components.collider.get(collider_id);
+                let start = *components.position.get(position_id);
 
-                if !check_collision(collider, &new_position, entity, components, entities) {
-                    let position = components.position.get_mut(position_id);
-                    position.x = new_position.x;
-                    position.y = new_position.y;
-                } else {
+                let velocity = {
+                    let body = components.dynamic_body.get_mut(body_id);
+                    if !body.kinematic {
+                        let velocity = integrate([body.vx, body.vy], body.gravity_scale, body.drag, body.max_speed);
+                        body.vx = velocity[0];
+                        body.vy = velocity[1];
+                    }
+                    [body.vx, body.vy]
+                };
 
-                    // TODO send some collision event somehow?
-                    // Can't really send to Entity itself, send it to
+                let new_position = world::Position { x: start.x + velocity[0], y: start.y + velocity[1] };
 
-                    if let Some(id) = entity.event_receiver {
+                // Sensors move freely, the TriggerSystem reports what they overlap
+                if collider.sensor {
+                    *components.position.get_mut(position_id) = new_position;
+                    continue;
+                }
 
-                        let event_receiver = components.event_receiver.get_mut(id);
-                        // TODO want other collider id, important for bullets hitting roaches!
-                        // TODO post event to both entities?
-                        event_receiver.post_event(world::Event::Collision(collider_id, collider_id));
-                        // also only want to fire this once...?
+                match check_collision(&collider, &new_position, entity, components, entities) {
+                    None => {
+                        *components.position.get_mut(position_id) = new_position;
 
-                    }
+                        // Not resting on anything, unless the PlayerSystem says otherwise next update
+                        components.dynamic_body.get_mut(body_id).ground = None;
+                    },
+                    Some(other_id) => {
+
+                        // Move up against whatever we hit instead of stopping short of it,
+                        // leaving a hair of space so we aren't overlapping it next update
+                        let speed = vec2_len(velocity);
+                        if speed > 0.0 {
+                            let direction = vec2_scale(velocity, 1.0 / speed);
+
+                            let hit = aabb_cast(&collider, [start.x, start.y], direction, speed, |other| other.collider != entity.collider, components, entities);
+                            if let Some(hit) = hit {
+                                let distance = (hit.distance - CAST_EPSILON).max(0.0);
+                                let position = components.position.get_mut(position_id);
+                                position.x = start.x + direction[0] * distance;
+                                position.y = start.y + direction[1] * distance;
+
+                                resolve_contact(entity, &entities[hit.entity], hit.normal, components);
+                            }
+                        }
 
+                        if let Some(id) = entity.event_receiver {
+                            let event_receiver = components.event_receiver.get_mut(id);
+                            event_receiver.post_event(world::Event::Collision(collider_id, other_id));
+                            // also only want to fire this once...?
+                        }
+
+                        // Let the other entity know it was hit too, from its point of view
+                        for entity_2 in entities.iter() {
+                            if let (Some(collider_2_id), Some(receiver_2_id)) = (entity_2.collider, entity_2.event_receiver) {
+                                if collider_2_id == other_id {
+                                    let event_receiver = components.event_receiver.get_mut(receiver_2_id);
+                                    event_receiver.post_event(world::Event::Collision(other_id, collider_id));
+                                }
+                            }
+                        }
+                    },
                 }
             }
         }
@@ -50,15 +213,13 @@ impl world::System for PhysicsSystem {
     }
 }
 
-fn check_collision(
+pub fn check_collision(
     collider_1: &world::AABBCollider,
     position_1: &world::Position,
     entity: &world::Entity,
     components: &world::Components,
     entities: &Vec<world::Entity>
-) -> bool {
-
-    let mut colliding = false;
+) -> Option<Id<world::AABBCollider>> {
 
     for entity_2 in entities.iter() {
 
@@ -74,15 +235,50 @@ fn check_collision(
             let collider_2 = components.collider.get(collider_2_id);
             let position_2 = components.position.get(position_2_id);
 
-            if aabb_intersect(collider_1, position_1, collider_2, position_2) {
-                colliding = true;
+            if collider_2.sensor {
+                continue;
+            }
+
+            if layers_collide(collider_1, collider_2) && aabb_intersect(collider_1, position_1, collider_2, position_2) {
+                return Some(collider_2_id);
             }
         }
     }
 
-    colliding
+    None
+}
+
+///
+/// Whether two colliders' layers and masks let them collide
+///
+pub fn layers_collide(a: &world::AABBCollider, b: &world::AABBCollider) -> bool {
+    a.mask & b.layer != 0 && b.mask & a.layer != 0
+}
+
+pub fn parse_layer(name: &str) -> Result<u32, String> {
+    match name.trim() {
+        "static" => Ok(LAYER_STATIC),
+        "player" => Ok(LAYER_PLAYER),
+        "enemy" => Ok(LAYER_ENEMY),
+        "player_projectile" => Ok(LAYER_PLAYER_PROJECTILE),
+        "enemy_projectile" => Ok(LAYER_ENEMY_PROJECTILE),
+        "pickup" => Ok(LAYER_PICKUP),
+        "trigger" => Ok(LAYER_TRIGGER),
+        "all" => Ok(ALL_LAYERS),
+        other => Err(format!("unknown collision layer '{}'", other)),
+    }
 }
 
+///
+/// Comma separated layer names, e.g. "static, enemy"
+///
+pub fn parse_layers(names: &str) -> Result<u32, String> {
+    let mut layers = 0;
+    for name in names.split(',') {
+        layers |= try!(parse_layer(name));
+    }
+    Ok(layers)
+}
 
 pub fn aabb_intersect(
     a_collider: &world::AABBCollider,
@@ -99,3 +295,496 @@ pub fn aabb_intersect(
 
     x_overlap && y_overlap
 }
+
+/// Slack for casts starting exactly against a collider, which float error can put slightly inside it
+const CAST_EPSILON: f32 = 0.0001;
+
+///
+/// First thing a raycast or aabb_cast ran into
+///
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Hit {
+    /// Index of the entity hit in the world's entities
+    pub entity: usize,
+    pub collider: Id<world::AABBCollider>,
+    /// Where the surface was touched
+    pub point: Vector2<f32>,
+    /// Facing back along the cast, out of the surface that was hit
+    pub normal: Vector2<f32>,
+    /// Distance travelled before touching
+    pub distance: f32,
+}
+
+///
+/// First collider on one of the `mask` layers along a ray from `origin` in `direction`
+/// (normalized) within `max_distance`, only entities `filter` returns true for are considered
+///
+pub fn raycast<F>(
+    origin: Vector2<f32>,
+    direction: Vector2<f32>,
+    max_distance: f32,
+    mask: u32,
+    filter: F,
+    components: &world::Components,
+    entities: &Vec<world::Entity>
+) -> Option<Hit> where F: Fn(&world::Entity) -> bool {
+
+    // A point that every layer accepts, so only the mask decides what's hit
+    let point = world::AABBCollider { width: 0.0, height: 0.0, layer: ALL_LAYERS, mask: mask, sensor: false };
+    aabb_cast(&point, origin, direction, max_distance, filter, components, entities)
+}
+
+///
+/// Move a box from `position` along `direction` (normalized) and find the first collider
+/// it touches within `max_distance`. Only colliders whose layers collide with it and
+/// entities `filter` returns true for are considered.
+///
+pub fn aabb_cast<F>(
+    collider: &world::AABBCollider,
+    position: Vector2<f32>,
+    direction: Vector2<f32>,
+    max_distance: f32,
+    filter: F,
+    components: &world::Components,
+    entities: &Vec<world::Entity>
+) -> Option<Hit> where F: Fn(&world::Entity) -> bool {
+
+    let mut nearest: Option<Hit> = None;
+
+    for (index, entity) in entities.iter().enumerate() {
+
+        if let (Some(collider_2_id), Some(position_2_id)) = (entity.collider, entity.position) {
+
+            if !filter(entity) {
+                continue;
+            }
+
+            let collider_2 = components.collider.get(collider_2_id);
+            let position_2 = components.position.get(position_2_id);
+
+            if collider_2.sensor || !layers_collide(collider, collider_2) {
+                continue;
+            }
+
+            if let Some((distance, normal)) = sweep_aabb(collider, position, direction, max_distance, collider_2, [position_2.x, position_2.y]) {
+
+                let closer = match nearest {
+                    Some(hit) => distance < hit.distance,
+                    None => true,
+                };
+
+                if closer {
+                    // Center of the box when it touched, less its extent towards the surface
+                    let center = vec2_add(position, vec2_scale(direction, distance));
+                    let extent = [collider.width / 2.0, collider.height / 2.0];
+                    let point = [center[0] - normal[0] * extent[0], center[1] - normal[1] * extent[1]];
+
+                    nearest = Some(Hit {
+                        entity: index,
+                        collider: collider_2_id,
+                        point: point,
+                        normal: normal,
+                        distance: distance,
+                    });
+                }
+            }
+        }
+    }
+
+    nearest
+}
+
+///
+/// Whether nothing on the `mask` layers that `filter` accepts is between `from` and `to`
+///
+pub fn line_of_sight<F>(
+    from: Vector2<f32>,
+    to: Vector2<f32>,
+    mask: u32,
+    filter: F,
+    components: &world::Components,
+    entities: &Vec<world::Entity>
+) -> bool where F: Fn(&world::Entity) -> bool {
+
+    let offset = vec2_sub(to, from);
+    let distance = vec2_len(offset);
+
+    if distance <= 0.0 {
+        return true;
+    }
+
+    raycast(from, vec2_scale(offset, 1.0 / distance), distance, mask, filter, components, entities).is_none()
+}
+
+///
+/// Distance `a` can move along `direction` before touching `b`, and the normal of
+/// the face it touches. Boxes only grazing each other's sides don't count, and
+/// neither does `b` if `a` already overlaps it.
+///
+pub fn sweep_aabb(
+    a_collider: &world::AABBCollider,
+    a_position: Vector2<f32>,
+    direction: Vector2<f32>,
+    max_distance: f32,
+    b_collider: &world::AABBCollider,
+    b_position: Vector2<f32>
+) -> Option<(f32, Vector2<f32>)> {
+
+    // Sweeping a box is the same as casting its center at the other box grown by its size
+    let half_size = [(a_collider.width + b_collider.width) / 2.0, (a_collider.height + b_collider.height) / 2.0];
+
+    let mut entry = Float::neg_infinity();
+    let mut exit = max_distance;
+    let mut normal = [0.0, 0.0];
+
+    for axis in 0..2 {
+        let min = b_position[axis] - half_size[axis];
+        let max = b_position[axis] + half_size[axis];
+
+        if direction[axis] == 0.0 {
+            if a_position[axis] <= min || a_position[axis] >= max {
+                return None;
+            }
+            continue;
+        }
+
+        let (near, far) = if direction[axis] > 0.0 { (min, max) } else { (max, min) };
+        let near_time = (near - a_position[axis]) / direction[axis];
+        let far_time = (far - a_position[axis]) / direction[axis];
+
+        if near_time > entry {
+            entry = near_time;
+            normal = [0.0, 0.0];
+            normal[axis] = -direction[axis].signum();
+        }
+        exit = exit.min(far_time);
+    }
+
+    if entry < -CAST_EPSILON || entry > exit || entry > max_distance {
+        return None;
+    }
+
+    Some((entry.max(0.0), normal))
+}
+
+///
+/// Velocity after an update of gravity and drag, limited to `max_speed`
+///
+pub fn integrate(velocity: Vector2<f32>, gravity_scale: f32, drag: f32, max_speed: Option<f32>) -> Vector2<f32> {
+
+    let velocity = vec2_add(velocity, vec2_scale(GRAVITY, gravity_scale));
+    let velocity = vec2_scale(velocity, (1.0 - drag).max(0.0));
+
+    match max_speed {
+        Some(max_speed) => {
+            let speed = vec2_len(velocity);
+            if speed > max_speed {
+                vec2_scale(velocity, max_speed / speed)
+            } else {
+                velocity
+            }
+        },
+        None => velocity,
+    }
+}
+
+///
+/// Velocity after hitting something immovable with the given `normal`:
+/// bounced back by `restitution` and slowed along the surface by `friction`
+///
+pub fn bounce(velocity: Vector2<f32>, normal: Vector2<f32>, restitution: f32, friction: f32) -> Vector2<f32> {
+
+    let approach = vec2_dot(velocity, normal);
+    if approach >= 0.0 {
+        return velocity;
+    }
+
+    let normal_velocity = vec2_scale(normal, approach);
+    let tangent_velocity = vec2_sub(velocity, normal_velocity);
+
+    vec2_sub(
+        vec2_scale(tangent_velocity, (1.0 - friction).max(0.0)),
+        vec2_scale(normal_velocity, restitution)
+    )
+}
+
+///
+/// Impulse on `a` from hitting `b`, `normal` pointing from `b` towards `a`.
+/// `b` gets the opposite impulse. Zero if they're already separating.
+///
+pub fn collision_impulse(
+    a_velocity: Vector2<f32>,
+    a_inverse_mass: f32,
+    b_velocity: Vector2<f32>,
+    b_inverse_mass: f32,
+    normal: Vector2<f32>,
+    restitution: f32
+) -> Vector2<f32> {
+
+    let approach = vec2_dot(vec2_sub(a_velocity, b_velocity), normal);
+    let inverse_mass = a_inverse_mass + b_inverse_mass;
+
+    if approach >= 0.0 || inverse_mass <= 0.0 {
+        return [0.0, 0.0];
+    }
+
+    vec2_scale(normal, -(1.0 + restitution) * approach / inverse_mass)
+}
+
+///
+/// Change the velocities of a body and what it ran into. Anything without
+/// a dynamic body, or with a kinematic one, is treated as immovable.
+/// Projectiles and sensors don't push anything, a bullet's knockback is
+/// applied by the HealthSystem instead.
+///
+fn resolve_contact(entity: &world::Entity, other: &world::Entity, normal: Vector2<f32>, components: &mut world::Components) {
+
+    if is_projectile_or_sensor(entity, components) || is_projectile_or_sensor(other, components) {
+        return;
+    }
+
+    let body_id = entity.dynamic_body.unwrap();
+
+    let other_body_id = match other.dynamic_body {
+        Some(other_body_id) if !components.dynamic_body.get(other_body_id).kinematic => other_body_id,
+        _ => {
+            let body = components.dynamic_body.get_mut(body_id);
+            if !body.kinematic {
+                let velocity = bounce([body.vx, body.vy], normal, body.restitution, body.friction);
+                body.vx = velocity[0];
+                body.vy = velocity[1];
+
+                // Landed on top of it, so ride along if it moves
+                if vec2_dot(normal, GRAVITY) < 0.0 && body.gravity_scale > 0.0 {
+                    body.ground = other.collider;
+                }
+            }
+            return;
+        }
+    };
+
+    let impulse = {
+        let body = components.dynamic_body.get(body_id);
+        let other_body = components.dynamic_body.get(other_body_id);
+        collision_impulse(
+            [body.vx, body.vy],
+            body.inverse_mass(),
+            [other_body.vx, other_body.vy],
+            other_body.inverse_mass(),
+            normal,
+            body.restitution.min(other_body.restitution)
+        )
+    };
+
+    components.dynamic_body.get_mut(body_id).apply_impulse(impulse);
+    components.dynamic_body.get_mut(other_body_id).apply_impulse(vec2_scale(impulse, -1.0));
+}
+
+fn is_projectile_or_sensor(entity: &world::Entity, components: &world::Components) -> bool {
+    match entity.collider {
+        Some(collider_id) => {
+            let collider = components.collider.get(collider_id);
+            collider.sensor || collider.layer & LAYER_PLAYER_PROJECTILE != 0
+        },
+        None => false
+    }
+}
+
+///
+/// Move a kinematic body along its path, carrying bodies standing on it and
+/// shoving aside anything else in the way
+///
+fn move_platform(platform: &world::Entity, components: &mut world::Components, entities: &Vec<world::Entity>) {
+
+    let collider_id = platform.collider.unwrap();
+    let position_id = platform.position.unwrap();
+    let collider = *components.collider.get(collider_id);
+
+    let start = *components.position.get(position_id);
+    let velocity = components.path_follower.get_mut(platform.path_follower.unwrap()).velocity([start.x, start.y]);
+
+    {
+        let body = components.dynamic_body.get_mut(platform.dynamic_body.unwrap());
+        body.vx = velocity[0];
+        body.vy = velocity[1];
+    }
+
+    if velocity == [0.0, 0.0] {
+        return;
+    }
+
+    let end = world::Position { x: start.x + velocity[0], y: start.y + velocity[1] };
+    *components.position.get_mut(position_id) = end;
+
+    for entity in entities.iter() {
+        if let (Some(body_id), Some(collider_2_id), Some(position_2_id)) = (entity.dynamic_body, entity.collider, entity.position) {
+
+            if entity.path_follower.is_some() {
+                continue;
+            }
+
+            let (kinematic, riding) = {
+                let body = components.dynamic_body.get(body_id);
+                (body.kinematic, body.ground == Some(collider_id))
+            };
+
+            let collider_2 = *components.collider.get(collider_2_id);
+            let position_2 = *components.position.get(position_2_id);
+
+            if kinematic || collider_2.sensor || !layers_collide(&collider, &collider_2) {
+                continue;
+            }
+
+            let pushed = !riding && aabb_intersect(&collider, &end, &collider_2, &position_2);
+
+            if !riding && !pushed {
+                continue;
+            }
+
+            // Move the same amount as the platform. Riders stay put rather than be dragged
+            // into a wall, anything being pushed has to get out of the way regardless.
+            let moved = world::Position { x: position_2.x + velocity[0], y: position_2.y + velocity[1] };
+            let blocked = check_collision(&collider_2, &moved, entity, components, entities).is_some();
+            if !blocked || pushed {
+                *components.position.get_mut(position_2_id) = moved;
+            }
+
+            if pushed {
+                // Keep up with the platform so it doesn't run straight back into us
+                let body = components.dynamic_body.get_mut(body_id);
+                let speed = vec2_len(velocity);
+                let direction = vec2_scale(velocity, 1.0 / speed);
+                let along = vec2_dot([body.vx, body.vy], direction);
+                if along < speed {
+                    body.vx += direction[0] * (speed - along);
+                    body.vy += direction[1] * (speed - along);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use vecmath::*;
+    use std::num::Float;
+
+    use world;
+    use super::{integrate, bounce, collision_impulse, GRAVITY};
+
+    fn assert_close(actual: Vector2<f32>, expected: Vector2<f32>) {
+        assert!((actual[0] - expected[0]).abs() < 1e-5 && (actual[1] - expected[1]).abs() < 1e-5,
+            "expected {:?}, got {:?}", expected, actual);
+    }
+
+    const FLOOR: Vector2<f32> = [0.0, -1.0];
+
+    #[test]
+    fn integrate_scales_gravity() {
+        assert_close(integrate([1.0, 2.0], 0.0, 0.0, None), [1.0, 2.0]);
+        assert_close(integrate([0.0, 0.0], 1.0, 0.0, None), GRAVITY);
+        assert_close(integrate([0.0, 0.0], 2.0, 0.0, None), vec2_scale(GRAVITY, 2.0));
+        assert_close(integrate([0.0, 0.0], -1.0, 0.0, None), vec2_scale(GRAVITY, -1.0));
+    }
+
+    #[test]
+    fn integrate_applies_drag() {
+        assert_close(integrate([2.0, -4.0], 0.0, 0.5, None), [1.0, -2.0]);
+        assert_close(integrate([2.0, -4.0], 0.0, 1.5, None), [0.0, 0.0]);
+    }
+
+    #[test]
+    fn integrate_clamps_to_max_speed() {
+        assert_close(integrate([3.0, 4.0], 0.0, 0.0, Some(1.0)), [0.6, 0.8]);
+        assert_close(integrate([0.3, 0.4], 0.0, 0.0, Some(1.0)), [0.3, 0.4]);
+    }
+
+    #[test]
+    fn bounce_restitution() {
+        assert_close(bounce([0.5, 2.0], FLOOR, 0.0, 0.0), [0.5, 0.0]);
+        assert_close(bounce([0.5, 2.0], FLOOR, 1.0, 0.0), [0.5, -2.0]);
+        assert_close(bounce([0.5, 2.0], FLOOR, 0.5, 0.0), [0.5, -1.0]);
+    }
+
+    #[test]
+    fn bounce_friction_slows_along_the_surface() {
+        assert_close(bounce([0.5, 2.0], FLOOR, 0.0, 0.5), [0.25, 0.0]);
+        assert_close(bounce([0.5, 2.0], FLOOR, 0.0, 1.0), [0.0, 0.0]);
+        assert_close(bounce([2.0, 0.5], [-1.0, 0.0], 1.0, 0.5), [-2.0, 0.25]);
+    }
+
+    #[test]
+    fn bounce_ignores_separating_velocity() {
+        assert_close(bounce([0.5, -2.0], FLOOR, 1.0, 0.5), [0.5, -2.0]);
+    }
+
+    #[test]
+    fn equal_masses_swap_velocities() {
+        // a on the left running into b at rest, normal from b towards a
+        let impulse = collision_impulse([1.0, 0.0], 1.0, [0.0, 0.0], 1.0, [-1.0, 0.0], 1.0);
+        assert_close(impulse, [-1.0, 0.0]);
+        assert_close(vec2_add([1.0, 0.0], impulse), [0.0, 0.0]);
+        assert_close(vec2_sub([0.0, 0.0], impulse), [1.0, 0.0]);
+    }
+
+    #[test]
+    fn unequal_masses_move_together_without_restitution() {
+        let impulse = collision_impulse([1.0, 0.0], 1.0, [0.0, 0.0], 1.0 / 3.0, [-1.0, 0.0], 0.0);
+        assert_close(impulse, [-0.75, 0.0]);
+        assert_close(vec2_add([1.0, 0.0], impulse), [0.25, 0.0]);
+        assert_close(vec2_sub([0.0, 0.0], vec2_scale(impulse, 1.0 / 3.0)), [0.25, 0.0]);
+    }
+
+    #[test]
+    fn separating_bodies_get_no_impulse() {
+        assert_close(collision_impulse([-1.0, 0.0], 1.0, [0.0, 0.0], 1.0, [-1.0, 0.0], 1.0), [0.0, 0.0]);
+        assert_close(collision_impulse([0.0, 0.0], 1.0, [0.0, 0.0], 1.0, [-1.0, 0.0], 1.0), [0.0, 0.0]);
+    }
+
+    #[test]
+    fn immovable_bodies() {
+        // Only a moves, bouncing straight back off b
+        let impulse = collision_impulse([1.0, 0.0], 1.0, [0.0, 0.0], 0.0, [-1.0, 0.0], 1.0);
+        assert_close(vec2_add([1.0, 0.0], impulse), [-1.0, 0.0]);
+
+        // Neither can move
+        assert_close(collision_impulse([1.0, 0.0], 0.0, [0.0, 0.0], 0.0, [-1.0, 0.0], 1.0), [0.0, 0.0]);
+    }
+
+    #[test]
+    fn inverse_mass() {
+        let mut body = world::DynamicBody::new(0.0, 0.0);
+        assert_eq!(body.inverse_mass(), 1.0);
+
+        body.mass = 2.0;
+        assert_eq!(body.inverse_mass(), 0.5);
+
+        body.mass = 0.0;
+        assert_eq!(body.inverse_mass(), 0.0);
+
+        body.mass = -1.0;
+        assert_eq!(body.inverse_mass(), 0.0);
+
+        body.mass = 2.0;
+        body.kinematic = true;
+        assert_eq!(body.inverse_mass(), 0.0);
+    }
+
+    #[test]
+    fn apply_impulse() {
+        let mut body = world::DynamicBody::new(1.0, 0.0);
+        body.mass = 2.0;
+        body.apply_impulse([1.0, -2.0]);
+        assert_close([body.vx, body.vy], [1.5, -1.0]);
+
+        let mut kinematic = world::DynamicBody::new(1.0, 0.0);
+        kinematic.kinematic = true;
+        kinematic.apply_impulse([1.0, -2.0]);
+        assert_close([kinematic.vx, kinematic.vy], [1.0, 0.0]);
+
+        let mut massless = world::DynamicBody::new(1.0, 0.0);
+        massless.mass = 0.0;
+        massless.apply_impulse([1.0, -2.0]);
+        assert_close([massless.vx, massless.vy], [1.0, 0.0]);
+    }
+}
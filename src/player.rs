@@ -1,41 +1,29 @@
 use graphics::Context;
 use opengl_graphics::GlGraphics;
 use vecmath::*;
-use openal::al;
 use std::num::Float;
-use std::f64;
+use std::f32;
+use id::Id;
 
 use world;
 use physics;
-use audio;
-
-use world::Weapon;
-
-use std::rc::Rc;
-use std::path::Path;
-use opengl_graphics::Texture;
+use units;
+use time;
+
+/// How close a surface has to be for the player to attach to it
+const SURFACE_PROBE: f32 = units::UNITS_PER_TILE / 16.0;
+/// How far the player can get from its surface before letting go, more than
+/// SURFACE_PROBE so it doesn't flicker between attached and flying
+const SURFACE_RELEASE: f32 = units::UNITS_PER_TILE / 8.0;
+/// Fraction of move_speed the player has to be going to play walk animations
+const WALK_ANIM_THRESHOLD: f32 = 0.5;
+/// Updates taken to swing round an outer corner
+const CORNER_TURN_UPDATES: u32 = 6;
+/// Degrees the sprite turns per update, a right angle over a corner turn
+const ROTATION_SPEED: f64 = 90.0 / CORNER_TURN_UPDATES as f64;
 
 pub struct PlayerSystem;
 
-impl Weapon {
-
-    pub fn new() -> Weapon {
-
-        let mut fire_sound = al::Buffer::gen();
-        audio::load_buffer("assets/Shoot.wav", &mut fire_sound);
-
-        world::Weapon {
-            fire_delay: 0.05,
-            bullet_speed: 3.0,
-            fire_timer: 0.0,
-            fire_sound: fire_sound,
-            firing: false,
-            fire_direction: [0.0, 0.0],
-        }
-    }
-
-}
-
 impl world::System for PlayerSystem {
 
     fn update(&mut self, control_state: &world::ControlState, components: &mut world::Components, entities: &mut Vec<world::Entity>) {
@@ -44,9 +32,37 @@ impl world::System for PlayerSystem {
         for entity in entities.iter() {
             if let (Some(player_id), Some(body_id), Some(animator_id)) = (entity.player_controller, entity.dynamic_body, entity.sprite_animator) {
 
+                // Dead or knocked back players can't move or shoot, but still fall
+                if let Some(health_id) = entity.health {
+                    if components.health.get(health_id).is_stunned(time::precise_time_s()) {
+                        if let Some(weapon_id) = entity.weapon {
+                            components.weapon.get_mut(weapon_id).firing = false;
+                        }
+                        components.player_controller.get_mut(player_id).corner_turn = None;
+                        update_walk_state(entity, components, entities);
+                        update_fall(entity, components, entities);
+                        continue;
+                    }
+                }
+
+                // Nothing else moves the player while it swings round a corner
+                if turn_corner(entity, components) {
+                    continue;
+                }
+
                 // Update orientation
                 update_walk_state(entity, components, entities);
 
+                if let Some(weapon_id) = entity.weapon {
+                    let weapon = components.weapon.get_mut(weapon_id);
+                    if control_state.next_weapon {
+                        weapon.switch(1);
+                    }
+                    if control_state.previous_weapon {
+                        weapon.switch(-1);
+                    }
+                }
+
                 // Update aim direction
                 if let Some(aim_direction) = get_aim_direction(control_state) {
                     let player = components.player_controller.get_mut(player_id);
@@ -71,87 +87,6 @@ impl world::System for PlayerSystem {
 
             }
         }
-
-        let mut bullets = Vec::new();
-
-        // Update weapons...
-        for entity in entities.iter() {
-            if let (Some(weapon_id), Some(position_id), Some(source_id)) = (entity.weapon, entity.position, entity.audio_source) {
-
-                let weapon = components.weapon.get_mut(weapon_id);
-                let position = components.position.get(position_id).clone();
-                let audio_source = components.audio_source.get_mut(source_id);
-
-                weapon.fire_timer -= 0.001; // TODO need delta time..
-
-                if weapon.firing && !(weapon.fire_timer > 0.0) {
-                    audio_source.play_buffer(weapon.fire_sound.clone());
-                    weapon.fire_timer = weapon.fire_delay;
-
-                    let bullet_texture = Rc::new(Texture::from_path(&Path::new("./assets/Bullet.png")).unwrap());
-                    let mut sprite_renderer = world::SpriteRenderer::from_texture_region(
-                        bullet_texture.clone(),
-                        [0, 0, 8, 8],
-                    );
-
-                    let angle = weapon.fire_direction[1].atan2(weapon.fire_direction[0]) as f64 * 180.0 / f64::consts::PI;
-                    sprite_renderer.sprite.set_rotation(angle + 90.0);
-
-                    let velocity = vec2_scale(weapon.fire_direction, weapon.bullet_speed);
-
-                    let bullet_origin = vec2_add(vec2_scale(weapon.fire_direction, 32.0), [position.x, position.y]);
-
-                    let bullet_entity = world::Entity {
-                        position: Some(components.position.add(world::Position { x: bullet_origin[0], y: bullet_origin[1] })),
-                        sprite_renderer: Some(components.sprite_renderer.add(sprite_renderer)),
-                        sprite_animator: None,
-                        player_controller: None,
-                        camera_target: None,
-                        collider: Some(components.collider.add(world::AABBCollider { width: 8.0, height: 8.0 })),
-                        dynamic_body: Some(components.dynamic_body.add(world::DynamicBody { vx: velocity[0], vy: velocity[1] })),
-                        audio_source: None,
-                        weapon: None,
-                        bullet: Some(components.bullet.add(world::Bullet)),
-                        event_receiver: Some(components.event_receiver.add(world::EventReceiver::new())),
-                    };
-
-                    bullets.push(bullet_entity);
-
-                }
-
-            }
-        }
-
-        // TODO use some kind of bullet entity pool...
-
-        let (new_entities, reserve): (Vec<_>, _) = entities.drain().partition(|entity| {
-
-            if let (Some(bullet_id), Some(receiver_id)) = (entity.bullet, entity.event_receiver) {
-                let event_receiver = components.event_receiver.get(receiver_id);
-
-                let mut colliding = false;
-                for event in event_receiver.event_queue.iter() {
-                    match *event {
-                        world::Event::Collision(collider_1, collider_2) => {
-                            colliding = true
-                        },
-                    }
-                }
-
-                // filter out colliding bullets...
-                !colliding
-
-            } else {
-                true
-            }
-        });
-
-
-        *entities = new_entities;
-
-        while (bullets.len() > 0 ) {
-            entities.push(bullets.pop().unwrap());
-        }
     }
     fn render(&mut self, context: &Context, gl: &mut GlGraphics, components: &mut world::Components, entities: &mut Vec<world::Entity>) {
         // not implemented
@@ -186,8 +121,16 @@ fn get_aim_direction(control_state: &world::ControlState) -> Option<Vector2<f32>
     }
 }
 
+///
+/// Whether to play walk rather than idle animations, given the squared speed
+///
+fn is_walking(player: &world::PlayerController, speed2: f32) -> bool {
+    let threshold = player.move_speed * WALK_ANIM_THRESHOLD;
+    speed2 > threshold * threshold
+}
+
 fn aim_up_anim(player: &world::PlayerController, speed: f32) -> world::SpriteAnimation {
-    if speed > 0.1 {
+    if is_walking(player, speed) {
         player.walk_anim_aim_up.clone()
     } else {
         player.idle_anim_aim_up.clone()
@@ -195,7 +138,7 @@ fn aim_up_anim(player: &world::PlayerController, speed: f32) -> world::SpriteAni
 }
 
 fn aim_up_forward_anim(player: &world::PlayerController, speed: f32) -> world::SpriteAnimation {
-    if speed > 0.1 {
+    if is_walking(player, speed) {
         player.walk_anim_aim_up_forward.clone()
     } else {
         player.idle_anim_aim_up_forward.clone()
@@ -203,7 +146,7 @@ fn aim_up_forward_anim(player: &world::PlayerController, speed: f32) -> world::S
 }
 
 fn aim_down_forward_anim(player: &world::PlayerController, speed: f32) -> world::SpriteAnimation {
-    if speed > 0.1 {
+    if is_walking(player, speed) {
         player.walk_anim_aim_down_forward.clone()
     } else {
         player.idle_anim_aim_down_forward.clone()
@@ -211,7 +154,7 @@ fn aim_down_forward_anim(player: &world::PlayerController, speed: f32) -> world:
 }
 
 fn aim_down_anim(player: &world::PlayerController, speed: f32) -> world::SpriteAnimation {
-    if speed > 0.1 {
+    if is_walking(player, speed) {
         player.walk_anim_aim_down.clone()
     } else {
         player.idle_anim_aim_down.clone()
@@ -219,7 +162,7 @@ fn aim_down_anim(player: &world::PlayerController, speed: f32) -> world::SpriteA
 }
 
 fn aim_forward_anim(player: &world::PlayerController, speed: f32) -> world::SpriteAnimation {
-    if speed > 0.1 {
+    if is_walking(player, speed) {
         player.walk_anim.clone()
     } else {
         player.idle_anim.clone()
@@ -228,40 +171,391 @@ fn aim_forward_anim(player: &world::PlayerController, speed: f32) -> world::Spri
 
 fn update_control(player_entity: &world::Entity, control_state: &world::ControlState, components: &mut world::Components, entities: &Vec<world::Entity>) {
 
-    use world::PlayerState;
+    let now = time::precise_time_s();
 
-    let player = components.player_controller.get(player_entity.player_controller.unwrap());
-    let body = components.dynamic_body.get_mut(player_entity.dynamic_body.unwrap());
+    let position = {
+        let position = components.position.get(player_entity.position.unwrap());
+        [position.x, position.y]
+    };
+    let zone = level_gravity_zone(position, components, entities);
 
-    if player.state != PlayerState::Flying {
-        let mut velocity: Vector2<f32> = [0.0, 0.0];
+    if let Some(normal) = surface_normal(&components.player_controller.get(player_entity.player_controller.unwrap()).state) {
+        crawl(player_entity, control_state, normal, components, entities);
+    }
 
-        if control_state.move_up {
-            velocity = vec2_add(velocity, [0.0, -1.0]);
-        }
+    let player = components.player_controller.get_mut(player_entity.player_controller.unwrap());
+    let body = components.dynamic_body.get_mut(player_entity.dynamic_body.unwrap());
+    let audio_source = components.audio_source.get_mut(player_entity.audio_source.unwrap());
 
-        if control_state.move_down {
-            velocity = vec2_add(velocity, [0.0, 1.0]);
-        }
+    if control_state.jump_pressed {
+        player.jump_buffered_until = now + player.jump_buffer as f64;
+    }
 
-        if control_state.move_left {
-            velocity = vec2_add(velocity, [-1.0, 0.0]);
-        }
+    match surface_normal(&player.state) {
+        Some(normal) => {
+            // Fall back towards whatever we were last walking on
+            player.gravity_direction = vec2_scale(normal, -1.0);
+            player.coyote_until = now + player.coyote_time as f64;
+            player.jumping = false;
+        },
+        None => {
+            let mut velocity = [body.vx, body.vy];
+            let up = vec2_scale(player.gravity_direction, -1.0);
+
+            // Letting go of jump early cuts the rest of the climb short
+            if player.jumping {
+                let rising = vec2_dot(velocity, up);
+                if rising <= 0.0 {
+                    player.jumping = false;
+                } else if !control_state.jump {
+                    velocity = vec2_sub(velocity, vec2_scale(up, rising * (1.0 - player.jump_cut)));
+                    player.jumping = false;
+                }
+            }
 
-        if control_state.move_right {
-            velocity = vec2_add(velocity, [1.0, 0.0]);
-        }
+            body.vx = velocity[0];
+            body.vy = velocity[1];
 
-        if velocity != [0.0, 0.0] {
-            velocity = vec2_normalized(velocity);
-            velocity = vec2_scale(velocity, player.move_speed);
-        }
+            fall(player, body, zone);
+        },
+    }
+
+    // Jump off the surface we're on, or just left
+    if now <= player.jump_buffered_until && now <= player.coyote_until {
+        let normal = vec2_scale(player.gravity_direction, -1.0);
+        let velocity = jump_velocity([body.vx, body.vy], normal, player.jump_speed);
 
         body.vx = velocity[0];
         body.vy = velocity[1];
 
+        player.jumping = true;
+        player.jump_buffered_until = 0.0;
+        player.coyote_until = 0.0;
+
+        audio_source.play(player.jump_sound);
+    }
+}
+
+///
+/// Walk along the current surface, climbing onto walls in front and
+/// wrapping around edges rather than walking off them
+///
+fn crawl(player_entity: &world::Entity, control_state: &world::ControlState, normal: Vector2<f32>, components: &mut world::Components, entities: &Vec<world::Entity>) {
+
+    let input = get_move_direction(control_state);
+
+    let (direction, move_speed) = {
+        let player = components.player_controller.get(player_entity.player_controller.unwrap());
+        (crawl_direction(input, normal, player.crawl_input, player.crawl_direction), player.move_speed)
+    };
+
+    let position = {
+        let position = components.position.get(player_entity.position.unwrap());
+        [position.x, position.y]
+    };
+
+    let step = vec2_scale(direction, move_speed);
+    let next = vec2_add(position, step);
+
+    let mut new_normal = normal;
+    let mut new_direction = direction;
+    let mut velocity = step;
+    let mut corner_turn = None;
+
+    if direction != [0.0, 0.0] {
+        if is_blocked(player_entity, next, components, entities) {
+            // Inner corner, climb onto the surface in front
+            new_normal = vec2_scale(direction, -1.0);
+            new_direction = normal;
+            velocity = vec2_scale(new_direction, move_speed);
+        } else if !is_blocked(player_entity, vec2_sub(next, vec2_scale(normal, SURFACE_PROBE)), components, entities) {
+            // Outer corner, the surface ended so swing round onto its side
+            let collider = components.collider.get(player_entity.collider.unwrap());
+            let depth = if normal[0] == 0.0 { collider.height } else { collider.width };
+            let wrapped = vec2_sub(next, vec2_scale(normal, depth));
+
+            if !is_blocked(player_entity, wrapped, components, entities) {
+                new_normal = direction;
+                new_direction = vec2_scale(normal, -1.0);
+                velocity = [0.0, 0.0];
+                corner_turn = Some(world::CornerTurn {
+                    start: next,
+                    end: wrapped,
+                    pivot: vec2_sub(next, vec2_scale(vec2_add(normal, direction), depth / 2.0)),
+                    progress: 0,
+                });
+            }
+        }
+    }
+
+    let player = components.player_controller.get_mut(player_entity.player_controller.unwrap());
+    player.state = state_for_normal(new_normal);
+    player.crawl_input = input;
+    player.crawl_direction = new_direction;
+    player.corner_turn = corner_turn;
+
+    let body = components.dynamic_body.get_mut(player_entity.dynamic_body.unwrap());
+    body.vx = velocity[0];
+    body.vy = velocity[1];
+}
+
+///
+/// Move one update further round the corner being turned, if any.
+/// Returns false once the player is back on a surface.
+///
+fn turn_corner(player_entity: &world::Entity, components: &mut world::Components) -> bool {
+
+    let player = components.player_controller.get_mut(player_entity.player_controller.unwrap());
+
+    let turn = match player.corner_turn {
+        Some(turn) => world::CornerTurn { progress: turn.progress + 1, ..turn },
+        None => return false,
+    };
+
+    let next = corner_position(&turn, turn.progress as f32 / CORNER_TURN_UPDATES as f32);
+    player.corner_turn = if turn.progress < CORNER_TURN_UPDATES { Some(turn) } else { None };
+    player.sprite_rotation = rotate_towards(player.sprite_rotation, surface_angle(&player.state), ROTATION_SPEED);
+
+    let position = components.position.get_mut(player_entity.position.unwrap());
+    position.x = next[0];
+    position.y = next[1];
+
+    let body = components.dynamic_body.get_mut(player_entity.dynamic_body.unwrap());
+    body.vx = 0.0;
+    body.vy = 0.0;
+
+    components.sprite_renderer.get_mut(player_entity.sprite_renderer.unwrap()).sprite.set_rotation(player.sprite_rotation);
+
+    true
+}
+
+///
+/// Position `t` of the way round a corner, swinging out on a quarter circle
+/// so the player never cuts through the corner itself
+///
+pub fn corner_position(turn: &world::CornerTurn, t: f32) -> Vector2<f32> {
+    let angle = t.max(0.0).min(1.0) * f32::consts::FRAC_PI_2;
+    let from = vec2_sub(turn.start, turn.pivot);
+    let to = vec2_sub(turn.end, turn.pivot);
+    vec2_add(turn.pivot, vec2_add(vec2_scale(from, angle.cos()), vec2_scale(to, angle.sin())))
+}
+
+///
+/// Degrees to draw the sprite at so its feet point at the surface
+///
+pub fn surface_angle(state: &world::PlayerState) -> f64 {
+
+    use world::PlayerState;
+
+    match *state {
+        PlayerState::Flying | PlayerState::OnFloor => 0.0,
+        PlayerState::OnLeftWall => 90.0,
+        PlayerState::OnCeiling => 180.0,
+        PlayerState::OnRightWall => 270.0,
+    }
+}
+
+///
+/// Turn from `current` towards `target` the short way round by at most
+/// `max_step` degrees, keeping the result in 0..360
+///
+pub fn rotate_towards(current: f64, target: f64, max_step: f64) -> f64 {
+
+    let difference = ((target - current) % 360.0 + 540.0) % 360.0 - 180.0;
+
+    let rotation = if difference.abs() <= max_step {
+        target
+    } else {
+        current + max_step * difference.signum()
+    };
+
+    (rotation % 360.0 + 360.0) % 360.0
+}
+
+fn get_move_direction(control_state: &world::ControlState) -> Vector2<f32> {
+
+    let mut direction: Vector2<f32> = [0.0, 0.0];
+
+    if control_state.move_up {
+        direction = vec2_add(direction, [0.0, -1.0]);
+    }
+
+    if control_state.move_down {
+        direction = vec2_add(direction, [0.0, 1.0]);
+    }
+
+    if control_state.move_left {
+        direction = vec2_add(direction, [-1.0, 0.0]);
+    }
+
+    if control_state.move_right {
+        direction = vec2_add(direction, [1.0, 0.0]);
+    }
+
+    if direction != [0.0, 0.0] {
+        vec2_normalized(direction)
+    } else {
+        direction
+    }
+}
+
+///
+/// Which way along the surface to walk for the keys held. Input pointing
+/// into or away from the surface is ignored, and keys held since the last
+/// corner keep going the same way around it.
+///
+pub fn crawl_direction(input: Vector2<f32>, normal: Vector2<f32>, held_input: Vector2<f32>, held_direction: Vector2<f32>) -> Vector2<f32> {
+
+    if input == [0.0, 0.0] {
+        return [0.0, 0.0];
+    }
+
+    if input == held_input && held_direction != [0.0, 0.0] && vec2_dot(held_direction, normal).abs() < 0.001 {
+        return held_direction;
+    }
+
+    let tangent = [-normal[1], normal[0]];
+    let along = vec2_dot(input, tangent);
+
+    if along > 0.001 {
+        tangent
+    } else if along < -0.001 {
+        vec2_scale(tangent, -1.0)
+    } else {
+        [0.0, 0.0]
+    }
+}
+
+///
+/// Whether the player's collider would overlap any walkable surface at `position`
+///
+fn is_blocked(player_entity: &world::Entity, position: Vector2<f32>, components: &world::Components, entities: &Vec<world::Entity>) -> bool {
+    let collider = surface_probe(components.collider.get(player_entity.collider.unwrap()));
+    let position = world::Position { x: position[0], y: position[1] };
+    physics::check_collision(&collider, &position, player_entity, components, entities).is_some()
+}
+
+///
+/// The player's collider, only colliding with static geometry so enemies
+/// and bullets can't be walked on
+///
+fn surface_probe(collider: &world::AABBCollider) -> world::AABBCollider {
+    world::AABBCollider { mask: physics::LAYER_STATIC, ..*collider }
+}
+
+///
+/// Direction away from the surface the player is attached to, None while flying
+///
+pub fn surface_normal(state: &world::PlayerState) -> Option<Vector2<f32>> {
+
+    use world::PlayerState;
+
+    match *state {
+        PlayerState::Flying => None,
+        PlayerState::OnFloor => Some([0.0, -1.0]),
+        PlayerState::OnCeiling => Some([0.0, 1.0]),
+        PlayerState::OnLeftWall => Some([1.0, 0.0]),
+        PlayerState::OnRightWall => Some([-1.0, 0.0]),
+    }
+}
+
+pub fn state_for_normal(normal: Vector2<f32>) -> world::PlayerState {
+
+    use world::PlayerState;
+
+    if normal[1] < -0.5 {
+        PlayerState::OnFloor
+    } else if normal[1] > 0.5 {
+        PlayerState::OnCeiling
+    } else if normal[0] > 0.5 {
+        PlayerState::OnLeftWall
+    } else {
+        PlayerState::OnRightWall
     }
+}
 
+///
+/// Launch along `normal`, keeping any speed along the surface
+///
+pub fn jump_velocity(velocity: Vector2<f32>, normal: Vector2<f32>, jump_speed: f32) -> Vector2<f32> {
+    let along_normal = vec2_dot(velocity, normal);
+    let tangential = vec2_sub(velocity, vec2_scale(normal, along_normal));
+    vec2_add(tangential, vec2_scale(normal, jump_speed))
+}
+
+///
+/// Apply gravity to a player that isn't on a surface, without reading any input
+///
+fn update_fall(player_entity: &world::Entity, components: &mut world::Components, entities: &Vec<world::Entity>) {
+
+    let position = {
+        let position = components.position.get(player_entity.position.unwrap());
+        [position.x, position.y]
+    };
+    let zone = level_gravity_zone(position, components, entities);
+
+    let player = components.player_controller.get_mut(player_entity.player_controller.unwrap());
+    let body = components.dynamic_body.get_mut(player_entity.dynamic_body.unwrap());
+
+    if surface_normal(&player.state).is_none() {
+        fall(player, body, zone);
+    }
+}
+
+///
+/// Pull the player along the gravity of the zone it's in, or its last gravity direction outside any zone
+///
+fn fall(player: &mut world::PlayerController, body: &mut world::DynamicBody, zone: Option<world::GravityZone>) {
+
+    let mut strength = 1.0;
+    if let Some(zone) = zone {
+        player.gravity_direction = zone.direction;
+        strength = zone.strength;
+    }
+
+    let velocity = apply_gravity([body.vx, body.vy], player.gravity_direction, player.gravity * strength, player.max_fall_speed);
+
+    body.vx = velocity[0];
+    body.vy = velocity[1];
+}
+
+///
+/// Accelerate along `direction`, limiting how fast we can fall that way
+///
+pub fn apply_gravity(velocity: Vector2<f32>, direction: Vector2<f32>, gravity: f32, max_fall_speed: f32) -> Vector2<f32> {
+
+    let velocity = vec2_add(velocity, vec2_scale(direction, gravity));
+
+    let falling = vec2_dot(velocity, direction);
+    if falling > max_fall_speed {
+        vec2_sub(velocity, vec2_scale(direction, falling - max_fall_speed))
+    } else {
+        velocity
+    }
+}
+
+///
+/// Gravity zone of the level containing `position`, if any
+///
+fn level_gravity_zone(position: Vector2<f32>, components: &world::Components, entities: &Vec<world::Entity>) -> Option<world::GravityZone> {
+    for entity in entities.iter() {
+        if let Some(level_id) = entity.level {
+            return gravity_zone_at(&components.level.get(level_id).gravity_zones[..], position);
+        }
+    }
+    None
+}
+
+///
+/// First zone containing `position`, zones listed first win where they overlap
+///
+pub fn gravity_zone_at(zones: &[world::GravityZone], position: Vector2<f32>) -> Option<world::GravityZone> {
+    zones.iter()
+        .find(|zone| {
+            position[0] >= zone.bounds[0] && position[0] < zone.bounds[2]
+                && position[1] >= zone.bounds[1] && position[1] < zone.bounds[3]
+        })
+        .map(|zone| *zone)
 }
 
 //
@@ -391,88 +685,217 @@ fn update_walk_state(player_entity: &world::Entity, components: &mut world::Comp
 
     use world::PlayerState;
 
-    let new_state = get_walk_state(player_entity, components, entities);
+    let (new_state, surface) = get_walk_state(player_entity, components, entities);
+
+    // Ride along with the surface if it moves
+    components.dynamic_body.get_mut(player_entity.dynamic_body.unwrap()).ground = surface;
 
     let player = components.player_controller.get_mut(player_entity.player_controller.unwrap());
     let sprite = &mut components.sprite_renderer.get_mut(player_entity.sprite_renderer.unwrap()).sprite;
     let audio_source = components.audio_source.get_mut(player_entity.audio_source.unwrap());
 
-    match new_state {
-        PlayerState::Flying => {
-
-            if player.state != PlayerState::Flying {
-                audio_source.play_buffer(player.jump_sound.clone());
-            }
-
-            sprite.set_rotation(0.0)
-        },
-        PlayerState::OnLeftWall => { sprite.set_rotation(90.0) },
-        PlayerState::OnRightWall => { sprite.set_rotation(270.0) },
-        PlayerState::OnCeiling => { sprite.set_rotation(180.0) },
-        PlayerState::OnFloor => { sprite.set_rotation(0.0) },
-    }
+    // Turn to face the new surface over a few updates rather than snapping
+    player.sprite_rotation = rotate_towards(player.sprite_rotation, surface_angle(&new_state), ROTATION_SPEED);
+    sprite.set_rotation(player.sprite_rotation);
 
     if player.state == PlayerState::Flying && new_state != PlayerState::Flying {
-        audio_source.play_buffer(player.land_sound.clone());
+        audio_source.play(player.land_sound);
     }
 
     player.state = new_state;
 }
 
-fn get_walk_state(player_entity: &world::Entity, components: &world::Components, entities: &Vec<world::Entity>) -> world::PlayerState {
+///
+/// State for the surface the player is on, and that surface's collider
+///
+fn get_walk_state(player_entity: &world::Entity, components: &world::Components, entities: &Vec<world::Entity>) -> (world::PlayerState, Option<Id<world::AABBCollider>>) {
 
     let player = components.player_controller.get(player_entity.player_controller.unwrap());
     let position = components.position.get(player_entity.position.unwrap());
-    let ground_check = &player.ground_check;
+    let collider = surface_probe(components.collider.get(player_entity.collider.unwrap()));
+
+    // Look for surfaces below, above, right and left
+    let directions = [[0.0, 1.0], [0.0, -1.0], [1.0, 0.0], [-1.0, 0.0]];
+
+    let mut contacts = Vec::new();
+    let mut colliders = Vec::new();
+    for &direction in directions.iter() {
+        let hit = physics::aabb_cast(
+            &collider,
+            [position.x, position.y],
+            direction,
+            SURFACE_RELEASE,
+            |other| other.collider != player_entity.collider,
+            components,
+            entities
+        );
+        if let Some(hit) = hit {
+            contacts.push((hit.normal, hit.distance));
+            colliders.push(hit.collider);
+        }
+    }
 
-    // find all intersections of ground_check with static geometry
-    let mut neighbouring_tiles = Vec::new();
+    let up = vec2_scale(player.gravity_direction, -1.0);
 
-    for entity_2 in entities.iter() {
+    match choose_surface(&contacts[..], surface_normal(&player.state), up) {
+        Some(normal) => {
+            let surface = contacts.iter().position(|&(contact_normal, _)| contact_normal == normal).map(|index| colliders[index]);
+            (state_for_normal(normal), surface)
+        },
+        None => (world::PlayerState::Flying, None),
+    }
+}
 
-        if let (Some(collider_2_id), Some(position_2_id)) = (entity_2.collider, entity_2.position) {
+///
+/// Pick the surface to walk on from the (normal, distance) contacts around the player.
+/// The current surface is kept out to SURFACE_RELEASE so the state doesn't flicker
+/// at corners, new surfaces have to be within SURFACE_PROBE. Of several new ones,
+/// the one facing most against gravity wins.
+///
+pub fn choose_surface(contacts: &[(Vector2<f32>, f32)], current: Option<Vector2<f32>>, up: Vector2<f32>) -> Option<Vector2<f32>> {
+
+    if let Some(current) = current {
+        if contacts.iter().any(|&(normal, distance)| normal == current && distance <= SURFACE_RELEASE) {
+            return Some(current);
+        }
+    }
 
-            // Don't check for collisions with self!
-            if player_entity.collider.unwrap() == collider_2_id {
-                continue;
-            }
+    let mut best: Option<(Vector2<f32>, f32)> = None;
 
-            let collider_2 = components.collider.get(collider_2_id);
-            let position_2 = components.position.get(position_2_id);
+    for &(normal, distance) in contacts.iter() {
+        if distance > SURFACE_PROBE {
+            continue;
+        }
 
-            if physics::aabb_intersect(ground_check, position, collider_2, position_2) {
-                neighbouring_tiles.push(position_2.clone());
-            }
+        let score = vec2_dot(normal, up);
+        best = match best {
+            Some((_, best_score)) if best_score >= score => best,
+            _ => Some((normal, score)),
+        };
+    }
+
+    best.map(|(normal, _)| normal)
+}
+
+#[cfg(test)]
+mod tests {
+    use vecmath::*;
+    use std::num::Float;
+
+    use world::{CornerTurn, PlayerState};
+    use super::{choose_surface, state_for_normal, surface_normal, SURFACE_PROBE, SURFACE_RELEASE};
+    use super::{corner_position, rotate_towards, surface_angle};
+
+    // Contact normals point away from the surface, y is down
+    const FLOOR: Vector2<f32> = [0.0, -1.0];
+    const CEILING: Vector2<f32> = [0.0, 1.0];
+    const LEFT_WALL: Vector2<f32> = [1.0, 0.0];
+    const RIGHT_WALL: Vector2<f32> = [-1.0, 0.0];
+
+    const UP: Vector2<f32> = [0.0, -1.0];
+    const DOWN: Vector2<f32> = [0.0, 1.0];
+
+    struct Case {
+        name: &'static str,
+        contacts: &'static [(Vector2<f32>, f32)],
+        current: Option<Vector2<f32>>,
+        up: Vector2<f32>,
+        expected: Option<Vector2<f32>>,
+    }
+
+    const NEAR: f32 = SURFACE_PROBE / 2.0;
+    /// Too far to attach to, close enough to stay on
+    const BETWEEN: f32 = (SURFACE_PROBE + SURFACE_RELEASE) / 2.0;
+    const FAR: f32 = SURFACE_RELEASE * 2.0;
+
+    const CASES: &'static [Case] = &[
+        Case { name: "nothing around", contacts: &[], current: None, up: UP, expected: None },
+        Case { name: "floor", contacts: &[(FLOOR, 0.0)], current: None, up: UP, expected: Some(FLOOR) },
+        Case { name: "left wall", contacts: &[(LEFT_WALL, NEAR)], current: None, up: UP, expected: Some(LEFT_WALL) },
+        Case { name: "right wall", contacts: &[(RIGHT_WALL, 0.0)], current: None, up: UP, expected: Some(RIGHT_WALL) },
+        Case { name: "ceiling", contacts: &[(CEILING, 0.0)], current: None, up: UP, expected: Some(CEILING) },
+        Case { name: "ceiling under reversed gravity", contacts: &[(CEILING, 0.0), (LEFT_WALL, 0.0)], current: None, up: DOWN, expected: Some(CEILING) },
+
+        // Inner corners, touching two surfaces at once
+        Case { name: "inner corner landing", contacts: &[(FLOOR, 0.0), (RIGHT_WALL, 0.0)], current: None, up: UP, expected: Some(FLOOR) },
+        Case { name: "inner corner stays on wall", contacts: &[(FLOOR, 0.0), (RIGHT_WALL, 0.0)], current: Some(RIGHT_WALL), up: UP, expected: Some(RIGHT_WALL) },
+        Case { name: "inner corner stays on ceiling", contacts: &[(CEILING, 0.0), (LEFT_WALL, 0.0)], current: Some(CEILING), up: UP, expected: Some(CEILING) },
+        Case { name: "inner corner wall to ceiling", contacts: &[(CEILING, 0.0), (LEFT_WALL, FAR)], current: Some(LEFT_WALL), up: UP, expected: Some(CEILING) },
+
+        // Outer corners, the surface drops away and the next one is round the edge
+        Case { name: "outer corner floor to wall", contacts: &[(LEFT_WALL, 0.0)], current: Some(FLOOR), up: UP, expected: Some(LEFT_WALL) },
+        Case { name: "outer corner wall to ceiling", contacts: &[(CEILING, NEAR)], current: Some(RIGHT_WALL), up: UP, expected: Some(CEILING) },
+        Case { name: "outer corner too far round", contacts: &[(LEFT_WALL, BETWEEN)], current: Some(FLOOR), up: UP, expected: None },
+
+        // Hysteresis
+        Case { name: "current surface kept out to release", contacts: &[(FLOOR, BETWEEN)], current: Some(FLOOR), up: UP, expected: Some(FLOOR) },
+        Case { name: "current surface kept at release", contacts: &[(FLOOR, SURFACE_RELEASE)], current: Some(FLOOR), up: UP, expected: Some(FLOOR) },
+        Case { name: "current surface lost past release", contacts: &[(FLOOR, FAR)], current: Some(FLOOR), up: UP, expected: None },
+        Case { name: "new surface not attached past probe", contacts: &[(FLOOR, BETWEEN)], current: None, up: UP, expected: None },
+        Case { name: "new surface attached at probe", contacts: &[(FLOOR, SURFACE_PROBE)], current: None, up: UP, expected: Some(FLOOR) },
+        Case { name: "new surface only within probe", contacts: &[(FLOOR, BETWEEN), (RIGHT_WALL, NEAR)], current: Some(CEILING), up: UP, expected: Some(RIGHT_WALL) },
+    ];
+
+    #[test]
+    fn choose_surface_table() {
+        for case in CASES.iter() {
+            let chosen = choose_surface(case.contacts, case.current, case.up);
+            assert!(chosen == case.expected, "{}: expected {:?}, got {:?}", case.name, case.expected, chosen);
         }
     }
 
-    if neighbouring_tiles.len() > 0 {
+    #[test]
+    fn states_round_trip_through_normals() {
+        let states = [PlayerState::OnFloor, PlayerState::OnCeiling, PlayerState::OnLeftWall, PlayerState::OnRightWall];
+        for state in states.iter() {
+            assert_eq!(state_for_normal(surface_normal(state).unwrap()), *state);
+        }
+        assert_eq!(surface_normal(&PlayerState::Flying), None);
+    }
 
-        let up = [0.0, -1.0];
-        let down = [0.0, 1.0];
-        let left = [-1.0, 0.0];
-        let right = [1.0, 0.0];
+    fn assert_close(actual: Vector2<f32>, expected: Vector2<f32>) {
+        assert!((actual[0] - expected[0]).abs() < 1e-4 && (actual[1] - expected[1]).abs() < 1e-4,
+            "expected {:?}, got {:?}", expected, actual);
+    }
 
-        let directions = vec![
-            (world::PlayerState::OnFloor, down),
-            (world::PlayerState::OnCeiling, up),
-            (world::PlayerState::OnRightWall, right),
-            (world::PlayerState::OnLeftWall, left),
-        ];
+    #[test]
+    fn corner_turn_swings_round_the_corner() {
+        // Walked right off the end of a floor whose top right corner is at the origin,
+        // onto the right side of the block
+        let turn = CornerTurn { start: [1.0, -1.0], end: [1.0, 1.0], pivot: [0.0, 0.0], progress: 0 };
 
-        let sum = neighbouring_tiles.iter().fold([0.0, 0.0], |v, p| [v[0] + p.x, v[1] + p.y]);
-        let avg = vec2_scale(sum, 1.0 / neighbouring_tiles.len() as f32);
+        assert_close(corner_position(&turn, 0.0), [1.0, -1.0]);
+        assert_close(corner_position(&turn, 1.0), [1.0, 1.0]);
 
-        let wall_to_player = vec2_normalized(vec2_sub([position.x, position.y], avg));
+        // Halfway round is out to the side, not through the block
+        let half = (2.0f32).sqrt();
+        assert_close(corner_position(&turn, 0.5), [half, 0.0]);
 
-        let (state, _) = directions.iter()
-            .map(|&(ref state, dir)| (state, vec2_len(vec2_sub(wall_to_player, dir))))
-            .max_by(|&(_, dist)| (dist * 10000.0) as i32)
-            .unwrap();
+        for i in 0..11 {
+            let position = corner_position(&turn, i as f32 / 10.0);
+            assert!(position[0] >= 1.0 - 1e-4, "cut through the corner at {:?}", position);
+            assert!((vec2_len(position) - half).abs() < 1e-4);
+        }
+    }
 
-        return state.clone();
+    #[test]
+    fn rotation_turns_the_short_way() {
+        assert_eq!(rotate_towards(0.0, 90.0, 15.0), 15.0);
+        assert_eq!(rotate_towards(0.0, 270.0, 15.0), 345.0);
+        assert_eq!(rotate_towards(350.0, 0.0, 15.0), 0.0);
+        assert_eq!(rotate_towards(345.0, 90.0, 15.0), 0.0);
+        assert_eq!(rotate_towards(180.0, 180.0, 15.0), 180.0);
+    }
 
+    #[test]
+    fn rotation_reaches_each_surface_in_a_corner_turn() {
+        let states = [PlayerState::OnLeftWall, PlayerState::OnCeiling, PlayerState::OnRightWall, PlayerState::OnFloor];
+        let mut rotation = 0.0;
+        for state in states.iter() {
+            for _ in 0..super::CORNER_TURN_UPDATES {
+                rotation = rotate_towards(rotation, surface_angle(state), super::ROTATION_SPEED);
+            }
+            assert_eq!(rotation, surface_angle(state));
+        }
     }
-    world::PlayerState::Flying
 }
-
@@ -0,0 +1,181 @@
+use graphics::Context;
+use opengl_graphics::GlGraphics;
+use input::keyboard::Key;
+
+use world;
+use text::BitmapFont;
+use units;
+
+///
+/// Groups of world systems, as a bit mask. Each state picks which groups
+/// it updates and which it renders.
+///
+pub type SystemSet = u32;
+
+pub const SYSTEMS_NONE: SystemSet = 0;
+/// Anything that changes the outcome of the game: player, weapons, physics, health...
+pub const SYSTEMS_GAMEPLAY: SystemSet = 1 << 0;
+/// Particles, animation, camera, audio and events, left running on screens
+/// shown over the level so deaths and explosions can play out
+pub const SYSTEMS_EFFECTS: SystemSet = 1 << 1;
+pub const SYSTEMS_HUD: SystemSet = 1 << 2;
+pub const SYSTEMS_ALL: SystemSet = SYSTEMS_GAMEPLAY | SYSTEMS_EFFECTS | SYSTEMS_HUD;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum GameState {
+    Title,
+    Playing,
+    Paused,
+    GameOver,
+    LevelComplete,
+    Credits,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Transition {
+    None,
+    /// Put a state on top of the current one, e.g. pausing
+    Push(GameState),
+    /// Return to the state underneath
+    Pop,
+    /// Replace the whole stack with a single state
+    Switch(GameState),
+    /// Reload the level and start playing
+    NewGame,
+    Quit,
+}
+
+impl GameState {
+
+    /// Systems updated in this state, the rest are paused
+    pub fn updated_systems(&self) -> SystemSet {
+        match *self {
+            GameState::Playing => SYSTEMS_ALL,
+            GameState::GameOver | GameState::LevelComplete => SYSTEMS_EFFECTS,
+            GameState::Paused | GameState::Title | GameState::Credits => SYSTEMS_NONE,
+        }
+    }
+
+    /// Systems drawn underneath this state's overlay
+    pub fn rendered_systems(&self) -> SystemSet {
+        match *self {
+            GameState::Playing | GameState::Paused => SYSTEMS_ALL,
+            GameState::GameOver | GameState::LevelComplete => SYSTEMS_GAMEPLAY | SYSTEMS_EFFECTS,
+            GameState::Title | GameState::Credits => SYSTEMS_NONE,
+        }
+    }
+
+    pub fn key_transition(&self, key: Key) -> Transition {
+        match (*self, key) {
+            (GameState::Title, Key::Return) => Transition::NewGame,
+            (GameState::Title, Key::C) => Transition::Push(GameState::Credits),
+            (GameState::Title, Key::Escape) => Transition::Quit,
+
+            (GameState::Playing, Key::Escape) | (GameState::Playing, Key::P) => Transition::Push(GameState::Paused),
+
+            (GameState::Paused, Key::Escape) | (GameState::Paused, Key::P) => Transition::Pop,
+            (GameState::Paused, Key::T) => Transition::Switch(GameState::Title),
+
+            (GameState::GameOver, Key::Return) => Transition::NewGame,
+            (GameState::GameOver, Key::Escape) => Transition::Switch(GameState::Title),
+
+            (GameState::LevelComplete, Key::Return) => Transition::Switch(GameState::Credits),
+            (GameState::LevelComplete, Key::Escape) => Transition::Switch(GameState::Title),
+
+            // Credits can be reached from the title (pushed) or at the end of the game (switched)
+            (GameState::Credits, Key::Return) | (GameState::Credits, Key::Escape) => Transition::Pop,
+
+            _ => Transition::None,
+        }
+    }
+
+    ///
+    /// Transitions caused by events posted in the world during the last frame
+    ///
+    pub fn event_transition(&self, event: &world::Event) -> Transition {
+        match (*self, *event) {
+            (GameState::Playing, world::Event::GameOver) => Transition::Switch(GameState::GameOver),
+            (GameState::Playing, world::Event::LevelComplete) => Transition::Switch(GameState::LevelComplete),
+            _ => Transition::None,
+        }
+    }
+
+    ///
+    /// Draw any text for this state in screen space
+    ///
+    pub fn render_overlay(&self, font: &BitmapFont, context: &Context, gl: &mut GlGraphics) {
+
+        use graphics::*;
+
+        let lines: &[&str] = match *self {
+            GameState::Title => &["SPACE ROACH EXTERMINATOR III", "", "ENTER - START", "C - CREDITS", "ESC - QUIT"],
+            GameState::Playing => &[],
+            GameState::Paused => &["PAUSED", "", "ESC - RESUME", "T - QUIT TO TITLE"],
+            GameState::GameOver => &["GAME OVER", "", "ENTER - TRY AGAIN", "ESC - TITLE"],
+            GameState::LevelComplete => &["LEVEL COMPLETE!", "", "ENTER - CONTINUE"],
+            GameState::Credits => &["SPACE ROACH EXTERMINATOR III", "", "BY STEVE JAHNS", "", "ENTER - BACK"],
+        };
+
+        if lines.len() == 0 {
+            return;
+        }
+
+        let screen_width = units::VIRTUAL_WIDTH as f64;
+        let screen_height = units::VIRTUAL_HEIGHT as f64;
+
+        // Dim the world behind the text
+        if self.rendered_systems() != SYSTEMS_NONE {
+            rectangle([0.0, 0.0, 0.0, 0.5], [0.0, 0.0, screen_width, screen_height], context.transform, gl);
+        }
+
+        let line_height = font.height() * 1.5;
+        let mut y = (screen_height - line_height * lines.len() as f64) / 2.0;
+
+        for line in lines.iter() {
+            let x = (screen_width - font.width(line)) / 2.0;
+            font.draw(line, x, y, [1.0, 1.0, 1.0, 1.0], context, gl);
+            y += line_height;
+        }
+    }
+}
+
+pub struct StateStack {
+    states: Vec<GameState>,
+}
+
+impl StateStack {
+
+    pub fn new(initial: GameState) -> StateStack {
+        StateStack { states: vec![initial] }
+    }
+
+    pub fn current(&self) -> GameState {
+        *self.states.last().unwrap()
+    }
+
+    ///
+    /// Apply a transition, the stack never becomes empty.
+    /// `NewGame` and `Quit` are left to the caller, which owns the world and window.
+    ///
+    pub fn apply(&mut self, transition: Transition) {
+        match transition {
+            Transition::Push(state) => self.states.push(state),
+            Transition::Pop => {
+                if self.states.len() > 1 {
+                    self.states.pop();
+                } else {
+                    self.states[0] = GameState::Title;
+                }
+            },
+            Transition::Switch(state) => {
+                self.states.clear();
+                self.states.push(state);
+            },
+            Transition::NewGame => {
+                self.states.clear();
+                self.states.push(GameState::Playing);
+            },
+            Transition::None | Transition::Quit => {},
+        }
+    }
+}
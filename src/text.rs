@@ -0,0 +1,119 @@
+use graphics::Context;
+use std::ascii::AsciiExt;
+use opengl_graphics::GlGraphics;
+
+///
+/// Tiny built-in 3x5 bitmap font drawn with rectangles, so the HUD
+/// doesn't depend on any font assets.
+///
+/// Each glyph is 5 rows of 3 bits, most significant bit on the left.
+/// Lowercase letters are drawn as uppercase, unknown characters as blanks.
+///
+pub struct BitmapFont {
+    /// Size of one font pixel, in screen pixels
+    pub scale: f64,
+}
+
+pub const GLYPH_WIDTH: f64 = 3.0;
+pub const GLYPH_HEIGHT: f64 = 5.0;
+pub const GLYPH_SPACING: f64 = 1.0;
+
+impl BitmapFont {
+
+    pub fn new(scale: f64) -> BitmapFont {
+        BitmapFont { scale: scale }
+    }
+
+    ///
+    /// Width of `text` in screen pixels
+    ///
+    pub fn width(&self, text: &str) -> f64 {
+        let count = text.chars().count() as f64;
+        if count == 0.0 {
+            return 0.0;
+        }
+        (count * (GLYPH_WIDTH + GLYPH_SPACING) - GLYPH_SPACING) * self.scale
+    }
+
+    pub fn height(&self) -> f64 {
+        GLYPH_HEIGHT * self.scale
+    }
+
+    ///
+    /// Draw `text` with its top left corner at `x`, `y`
+    ///
+    pub fn draw(&self, text: &str, x: f64, y: f64, color: [f32; 4], context: &Context, gl: &mut GlGraphics) {
+
+        use graphics::*;
+
+        let mut cursor = x;
+
+        for c in text.chars() {
+            if let Some(rows) = glyph(c) {
+                for (row, bits) in rows.iter().enumerate() {
+                    for column in 0..3 {
+                        if bits & (0b100 >> column) != 0 {
+                            let rect = [
+                                cursor + column as f64 * self.scale,
+                                y + row as f64 * self.scale,
+                                self.scale,
+                                self.scale,
+                            ];
+                            rectangle(color, rect, context.transform, gl);
+                        }
+                    }
+                }
+            }
+            cursor += (GLYPH_WIDTH + GLYPH_SPACING) * self.scale;
+        }
+    }
+}
+
+pub fn glyph(c: char) -> Option<[u8; 5]> {
+    let rows = match c.to_ascii_uppercase() {
+        '0' => [0b111, 0b101, 0b101, 0b101, 0b111],
+        '1' => [0b010, 0b110, 0b010, 0b010, 0b111],
+        '2' => [0b111, 0b001, 0b111, 0b100, 0b111],
+        '3' => [0b111, 0b001, 0b111, 0b001, 0b111],
+        '4' => [0b101, 0b101, 0b111, 0b001, 0b001],
+        '5' => [0b111, 0b100, 0b111, 0b001, 0b111],
+        '6' => [0b111, 0b100, 0b111, 0b101, 0b111],
+        '7' => [0b111, 0b001, 0b001, 0b001, 0b001],
+        '8' => [0b111, 0b101, 0b111, 0b101, 0b111],
+        '9' => [0b111, 0b101, 0b111, 0b001, 0b111],
+        'A' => [0b010, 0b101, 0b111, 0b101, 0b101],
+        'B' => [0b110, 0b101, 0b110, 0b101, 0b110],
+        'C' => [0b011, 0b100, 0b100, 0b100, 0b011],
+        'D' => [0b110, 0b101, 0b101, 0b101, 0b110],
+        'E' => [0b111, 0b100, 0b110, 0b100, 0b111],
+        'F' => [0b111, 0b100, 0b110, 0b100, 0b100],
+        'G' => [0b011, 0b100, 0b101, 0b101, 0b011],
+        'H' => [0b101, 0b101, 0b111, 0b101, 0b101],
+        'I' => [0b111, 0b010, 0b010, 0b010, 0b111],
+        'J' => [0b001, 0b001, 0b001, 0b101, 0b010],
+        'K' => [0b101, 0b101, 0b110, 0b101, 0b101],
+        'L' => [0b100, 0b100, 0b100, 0b100, 0b111],
+        'M' => [0b101, 0b111, 0b111, 0b101, 0b101],
+        'N' => [0b110, 0b101, 0b101, 0b101, 0b101],
+        'O' => [0b010, 0b101, 0b101, 0b101, 0b010],
+        'P' => [0b110, 0b101, 0b110, 0b100, 0b100],
+        'Q' => [0b010, 0b101, 0b101, 0b110, 0b011],
+        'R' => [0b110, 0b101, 0b110, 0b101, 0b101],
+        'S' => [0b011, 0b100, 0b010, 0b001, 0b110],
+        'T' => [0b111, 0b010, 0b010, 0b010, 0b010],
+        'U' => [0b101, 0b101, 0b101, 0b101, 0b111],
+        'V' => [0b101, 0b101, 0b101, 0b101, 0b010],
+        'W' => [0b101, 0b101, 0b111, 0b111, 0b101],
+        'X' => [0b101, 0b101, 0b010, 0b101, 0b101],
+        'Y' => [0b101, 0b101, 0b010, 0b010, 0b010],
+        'Z' => [0b111, 0b001, 0b010, 0b100, 0b111],
+        ':' => [0b000, 0b010, 0b000, 0b010, 0b000],
+        '/' => [0b001, 0b001, 0b010, 0b100, 0b100],
+        '-' => [0b000, 0b000, 0b111, 0b000, 0b000],
+        '.' => [0b000, 0b000, 0b000, 0b000, 0b010],
+        '!' => [0b010, 0b010, 0b010, 0b000, 0b010],
+        '%' => [0b101, 0b001, 0b010, 0b100, 0b101],
+        _ => return None,
+    };
+    Some(rows)
+}
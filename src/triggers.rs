@@ -0,0 +1,152 @@
+use graphics::Context;
+use opengl_graphics::GlGraphics;
+use std::mem;
+use id::Id;
+
+use world;
+use world::TriggerAction;
+use physics;
+use music::MusicTrack;
+
+///
+/// Tracks what's inside each sensor collider with a Trigger, posting
+/// TriggerEnter, TriggerStay and TriggerExit events to the trigger and to
+/// whatever entered it, and carrying out the trigger's action.
+///
+/// Goes after physics so it sees where things ended up this update.
+///
+pub struct TriggerSystem {
+    /// Colliders overlapping the trigger being updated
+    overlapping: Vec<Id<world::AABBCollider>>,
+    /// Events for the entity owning each collider
+    events: Vec<(Id<world::AABBCollider>, world::Event)>,
+    /// Music for the level to switch to
+    music: Option<MusicTrack>,
+}
+
+impl TriggerSystem {
+    pub fn new() -> TriggerSystem {
+        TriggerSystem {
+            overlapping: Vec::new(),
+            events: Vec::new(),
+            music: None,
+        }
+    }
+}
+
+impl world::System for TriggerSystem {
+
+    fn update(&mut self, control_state: &world::ControlState, components: &mut world::Components, entities: &mut Vec<world::Entity>) {
+
+        self.events.clear();
+        self.music = None;
+
+        for entity in entities.iter() {
+            if let (Some(trigger_id), Some(collider_id), Some(_)) = (entity.trigger, entity.collider, entity.position) {
+
+                find_overlapping(entity, &mut self.overlapping, components, entities);
+
+                let trigger = components.trigger.get_mut(trigger_id);
+
+                for &other_id in self.overlapping.iter() {
+
+                    let entered = !trigger.inside.contains(&other_id);
+
+                    let event = if entered {
+                        world::Event::TriggerEnter(collider_id, other_id)
+                    } else {
+                        world::Event::TriggerStay(collider_id, other_id)
+                    };
+                    self.events.push((collider_id, event));
+                    self.events.push((other_id, event));
+
+                    match trigger.action {
+                        TriggerAction::Checkpoint(point) if entered => {
+                            self.events.push((other_id, world::Event::Checkpoint(point)));
+                        },
+                        TriggerAction::Exit if entered => {
+                            self.events.push((other_id, world::Event::LevelComplete));
+                        },
+                        TriggerAction::Hazard(damage) => {
+                            self.events.push((other_id, world::Event::Damage(damage, [0.0, 0.0])));
+                        },
+                        TriggerAction::Music(ref track) if entered => {
+                            self.music = Some(track.clone());
+                        },
+                        _ => {},
+                    }
+                }
+
+                for &other_id in trigger.inside.iter() {
+                    if !self.overlapping.contains(&other_id) {
+                        let event = world::Event::TriggerExit(collider_id, other_id);
+                        self.events.push((collider_id, event));
+                        self.events.push((other_id, event));
+                    }
+                }
+
+                // Keep both allocations, last update's list becomes the next scratch buffer
+                mem::swap(&mut trigger.inside, &mut self.overlapping);
+            }
+        }
+
+        for &(collider_id, event) in self.events.iter() {
+            for entity in entities.iter() {
+                if let (Some(other_collider), Some(receiver_id)) = (entity.collider, entity.event_receiver) {
+                    if other_collider == collider_id {
+                        components.event_receiver.get_mut(receiver_id).post_event(event);
+                    }
+                }
+            }
+        }
+
+        if let Some(ref track) = self.music {
+            for entity in entities.iter() {
+                if let Some(level_id) = entity.level {
+                    components.level.get_mut(level_id).music = Some(track.clone());
+                }
+            }
+        }
+    }
+
+    fn render(&mut self, context: &Context, gl: &mut GlGraphics, components: &mut world::Components, entities: &mut Vec<world::Entity>) {
+        // not implemented
+    }
+}
+
+///
+/// Every non-sensor collider overlapping a trigger entity's collider that its layers accept
+///
+fn find_overlapping(trigger_entity: &world::Entity, overlapping: &mut Vec<Id<world::AABBCollider>>, components: &world::Components, entities: &Vec<world::Entity>) {
+
+    overlapping.clear();
+
+    let collider = components.collider.get(trigger_entity.collider.unwrap());
+    let position = components.position.get(trigger_entity.position.unwrap());
+
+    for entity in entities.iter() {
+        if let (Some(collider_2_id), Some(position_2_id)) = (entity.collider, entity.position) {
+
+            if trigger_entity.collider == Some(collider_2_id) {
+                continue;
+            }
+
+            let collider_2 = components.collider.get(collider_2_id);
+            let position_2 = components.position.get(position_2_id);
+
+            if !collider_2.sensor && physics::layers_collide(collider, collider_2)
+                && physics::aabb_intersect(collider, position, collider_2, position_2) {
+                overlapping.push(collider_2_id);
+            }
+        }
+    }
+}
+
+impl world::Trigger {
+    pub fn new(action: TriggerAction) -> world::Trigger {
+        world::Trigger {
+            action: action,
+            inside: Vec::new(),
+        }
+    }
+}
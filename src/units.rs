@@ -0,0 +1,69 @@
+///
+/// World units and screen scaling.
+///
+/// Everything in the world (positions, colliders, speeds) is measured in world units.
+/// By default one world unit is one tile, sprites are scaled down from their pixel
+/// size when they are created.
+///
+
+/// World units spanned by one tile
+pub const UNITS_PER_TILE: f32 = 1.0;
+
+/// Size of one tile in the tileset, in texture pixels
+pub const PIXELS_PER_TILE: f32 = 32.0;
+
+/// Resolution the game is rendered at before being scaled to the window
+pub const VIRTUAL_WIDTH: u32 = 640;
+pub const VIRTUAL_HEIGHT: u32 = 480;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ScaleMode {
+    /// Largest integer multiple of the virtual resolution that fits, with black bars around it
+    PixelPerfect,
+    /// Largest scale that fits while keeping aspect ratio, with black bars on one axis
+    Letterbox,
+}
+
+pub const SCALE_MODE: ScaleMode = ScaleMode::PixelPerfect;
+
+/// Convert a length in tiles to world units
+pub fn tiles(n: f32) -> f32 {
+    n * UNITS_PER_TILE
+}
+
+/// Convert a length in texture pixels to world units
+pub fn pixels(n: f32) -> f32 {
+    n / pixels_per_unit()
+}
+
+pub fn pixels_per_unit() -> f32 {
+    PIXELS_PER_TILE / UNITS_PER_TILE
+}
+
+///
+/// Region of the window to draw the virtual screen into, as [x, y, width, height]
+///
+pub fn viewport_rect(window_width: u32, window_height: u32, mode: ScaleMode) -> [i32; 4] {
+
+    let scale_x = window_width as f32 / VIRTUAL_WIDTH as f32;
+    let scale_y = window_height as f32 / VIRTUAL_HEIGHT as f32;
+    let fit = if scale_x < scale_y { scale_x } else { scale_y };
+
+    let scale = match mode {
+        ScaleMode::PixelPerfect => {
+            // Shrinking below 1x can't be pixel perfect, just fit the window
+            if fit >= 1.0 { fit.floor() } else { fit }
+        },
+        ScaleMode::Letterbox => fit,
+    };
+
+    let width = (VIRTUAL_WIDTH as f32 * scale) as i32;
+    let height = (VIRTUAL_HEIGHT as f32 * scale) as i32;
+
+    [
+        (window_width as i32 - width) / 2,
+        (window_height as i32 - height) / 2,
+        width,
+        height,
+    ]
+}
@@ -0,0 +1,651 @@
+use graphics::Context;
+use opengl_graphics::GlGraphics;
+use vecmath::*;
+use std::num::Float;
+use std::f32;
+use std::f64;
+use std::rc::Rc;
+use id::Id;
+
+use assets::{AssetManager, SharedTexture};
+use audio::SoundId;
+use mixer::SoundParams;
+use error::{self, Error};
+use world;
+use world::{Weapon, WeaponDefinition, ProjectilePrefab, FirePattern};
+use data;
+use event;
+use physics;
+use time;
+use units;
+
+/// Rapid fire is the first thing cut off when every voice is busy
+const FIRE_PRIORITY: u8 = 64;
+
+impl Weapon {
+
+    pub fn new(definitions: Vec<Rc<WeaponDefinition>>) -> Weapon {
+        world::Weapon {
+            definitions: definitions,
+            current: 0,
+            firing: false,
+            fire_direction: [0.0, 0.0],
+            next_fire_time: 0.0,
+            burst_remaining: 0,
+            charge_start: None,
+            heat: 0.0,
+            overheated: false,
+            last_update: time::precise_time_s(),
+        }
+    }
+
+    pub fn definition(&self) -> Rc<WeaponDefinition> {
+        self.definitions[self.current].clone()
+    }
+
+    ///
+    /// Cycle through weapons, `offset` is usually 1 or -1
+    ///
+    pub fn switch(&mut self, offset: i32) {
+        let count = self.definitions.len() as i32;
+        if count == 0 {
+            return;
+        }
+        self.current = (((self.current as i32 + offset) % count + count) % count) as usize;
+        self.burst_remaining = 0;
+        self.charge_start = None;
+    }
+}
+
+fn parse_pattern(name: &str) -> Result<FirePattern, String> {
+    match name {
+        "single" => Ok(FirePattern::Single),
+        "spread" => Ok(FirePattern::Spread),
+        "burst" => Ok(FirePattern::Burst),
+        "charge" => Ok(FirePattern::Charge),
+        _ => Err(format!("unknown fire pattern '{}'", name)),
+    }
+}
+
+fn parse_definition(section: &data::Section, texture: SharedTexture, fire_sound: SoundId) -> Result<WeaponDefinition, String> {
+
+    let sprite_size = try!(section.get_or("sprite_size", 8i32));
+
+    let projectile = ProjectilePrefab {
+        texture: texture,
+        region: [0, 0, sprite_size, sprite_size],
+        size: units::tiles(try!(section.get_or("size", 0.25))),
+        layer: try!(physics::parse_layer(section.get("layer").unwrap_or("player_projectile"))),
+        mask: try!(physics::parse_layers(section.get("collides_with").unwrap_or("static, enemy"))),
+        speed: units::tiles(try!(section.require("speed"))),
+        muzzle_offset: units::tiles(try!(section.get_or("muzzle_offset", 0.5))),
+        damage: try!(section.get_or("damage", 1.0)),
+        knockback: units::tiles(try!(section.get_or("knockback", 0.1))),
+        range: units::tiles(try!(section.get_or("range", 32.0))),
+        pierce: try!(section.get_or("pierce", 0)),
+        bounces: try!(section.get_or("bounces", 0)),
+        restitution: try!(section.get_or("restitution", 1.0)),
+    };
+
+    Ok(WeaponDefinition {
+        name: section.name.clone(),
+        pattern: try!(parse_pattern(try!(section.get("pattern").ok_or(format!("[{}] missing pattern", section.name))))),
+        fire_delay: try!(section.require("fire_delay")),
+        projectiles: try!(section.get_or("projectiles", 1)),
+        spread: try!(section.get_or("spread", 0.0f32)) * f32::consts::PI / 180.0,
+        burst_count: try!(section.get_or("burst_count", 1)),
+        burst_interval: try!(section.get_or("burst_interval", 0.0)),
+        charge_time: try!(section.get_or("charge_time", 0.0)),
+        charge_damage: try!(section.get_or("charge_damage", 1.0)),
+        heat_per_shot: try!(section.get_or("heat_per_shot", 0.0)),
+        cooling_rate: try!(section.get_or("cooling_rate", 0.5)),
+        shake: try!(section.get_or("shake", 0.0)),
+        fire_sound: fire_sound,
+        projectile: projectile,
+    })
+}
+
+///
+/// Load weapon definitions from a data file, one [section] per weapon, in switching order
+///
+pub fn load_definitions(name: &str, assets: &mut AssetManager) -> error::Result<Vec<Rc<WeaponDefinition>>> {
+
+    let path = assets.path(name);
+    let data_error = |e: String| Error::Data(path.display().to_string(), e);
+
+    let sections = try!(data::load(&path));
+
+    let mut definitions = Vec::new();
+    for section in sections.iter() {
+
+        let sound = try!(assets.load_sound(&try!(section.require::<String>("sound").map_err(&data_error))[..]));
+        let fire_sound = assets.sound(sound).id;
+
+        let sprite = try!(assets.load_texture(&try!(section.require::<String>("sprite").map_err(&data_error))[..]));
+        let texture = assets.shared_texture(sprite);
+
+        let definition = try!(parse_definition(section, texture, fire_sound).map_err(&data_error));
+        definitions.push(Rc::new(definition));
+    }
+
+    if definitions.len() == 0 {
+        return Err(data_error("no weapons defined".to_string()));
+    }
+
+    Ok(definitions)
+}
+
+///
+/// Direction of projectile `index` of `count` in a volley, fanned out evenly across `spread` radians
+///
+pub fn volley_direction(direction: Vector2<f32>, index: u32, count: u32, spread: f32) -> Vector2<f32> {
+
+    let base_angle = direction[1].atan2(direction[0]);
+
+    let offset = if count > 1 {
+        -spread / 2.0 + spread * index as f32 / (count - 1) as f32
+    } else {
+        0.0
+    };
+
+    let angle = base_angle + offset;
+    [angle.cos(), angle.sin()]
+}
+
+impl world::ProjectilePool {
+
+    pub fn new() -> world::ProjectilePool {
+        world::ProjectilePool {
+            free: Vec::new(),
+            allocated: 0,
+        }
+    }
+}
+
+fn projectile_collider(prefab: &ProjectilePrefab) -> world::AABBCollider {
+    world::AABBCollider {
+        width: prefab.size,
+        height: prefab.size,
+        layer: prefab.layer,
+        mask: prefab.mask,
+        sensor: false,
+    }
+}
+
+///
+/// Projectiles fly straight, bouncing is left to the WeaponSystem
+///
+fn projectile_body(prefab: &ProjectilePrefab, velocity: Vector2<f32>) -> world::DynamicBody {
+    let mut body = world::DynamicBody::new(velocity[0], velocity[1]);
+    body.restitution = prefab.restitution;
+    body.kinematic = true;
+    body
+}
+
+///
+/// Spawn a projectile, reusing a pooled bullet entity and its components if there is one
+///
+fn spawn_projectile(
+    recycled: Option<world::Entity>,
+    prefab: &ProjectilePrefab,
+    origin: Vector2<f32>,
+    direction: Vector2<f32>,
+    damage_scale: f32,
+    components: &mut world::Components
+) -> (world::Entity, bool) {
+
+    let angle = direction[1].atan2(direction[0]) as f64 * 180.0 / f64::consts::PI;
+    let velocity = vec2_scale(direction, prefab.speed);
+    let position = vec2_add(vec2_scale(direction, prefab.muzzle_offset), origin);
+
+    let bullet = world::Bullet {
+        damage: prefab.damage * damage_scale,
+        knockback: prefab.knockback,
+        distance_remaining: prefab.range,
+        pierce_remaining: prefab.pierce,
+        bounces_remaining: prefab.bounces,
+        last_hit: None,
+        expired: false,
+    };
+
+    if let Some(entity) = recycled {
+
+        *components.position.get_mut(entity.position.unwrap()) = world::Position { x: position[0], y: position[1] };
+        *components.collider.get_mut(entity.collider.unwrap()) = projectile_collider(prefab);
+        *components.dynamic_body.get_mut(entity.dynamic_body.unwrap()) = projectile_body(prefab, velocity);
+        *components.bullet.get_mut(entity.bullet.unwrap()) = bullet;
+
+        let sprite = &mut components.sprite_renderer.get_mut(entity.sprite_renderer.unwrap()).sprite;
+        sprite.set_texture(prefab.texture.get());
+        sprite.set_src_rect(prefab.region);
+        sprite.set_rotation(angle + 90.0);
+
+        // Don't let collisions from its last life leak into this one
+        let event_receiver = components.event_receiver.get_mut(entity.event_receiver.unwrap());
+        event_receiver.event_queue.clear();
+        event_receiver.pending_events.clear();
+
+        return (entity, false);
+    }
+
+    let mut sprite_renderer = world::SpriteRenderer::from_texture_region(prefab.texture.get(), prefab.region);
+    sprite_renderer.sprite.set_rotation(angle + 90.0);
+
+    let entity = world::Entity {
+        position: Some(components.position.add(world::Position { x: position[0], y: position[1] })),
+        sprite_renderer: Some(components.sprite_renderer.add(sprite_renderer)),
+        sprite_animator: None,
+        player_controller: None,
+        camera_target: None,
+        camera: None,
+        collider: Some(components.collider.add(projectile_collider(prefab))),
+        dynamic_body: Some(components.dynamic_body.add(projectile_body(prefab, velocity))),
+        audio_source: None,
+        weapon: None,
+        bullet: Some(components.bullet.add(bullet)),
+        event_receiver: Some(components.event_receiver.add(world::EventReceiver::new())),
+        particle_emitter: None,
+        health: None,
+        score: None,
+        level: None,
+        enemy: None,
+        projectile_pool: None,
+        trigger: None,
+        path_follower: None,
+        destructible: None,
+        hive: None,
+    };
+
+    (entity, true)
+}
+
+///
+/// Fires weapons according to their fire pattern and moves bullets along:
+/// bullets expire at the end of their range, pierce through damageable
+/// entities and bounce off everything else.
+///
+/// Expired bullets go back to the world's ProjectilePool, and scratch
+/// buffers are kept between frames, so firing doesn't allocate once warmed up.
+///
+pub struct WeaponSystem {
+    shots: Vec<(Rc<WeaponDefinition>, Vector2<f32>, Vector2<f32>, f32)>,
+    damageable: Vec<Id<world::AABBCollider>>,
+}
+
+impl WeaponSystem {
+    pub fn new() -> WeaponSystem {
+        WeaponSystem {
+            shots: Vec::new(),
+            damageable: Vec::new(),
+        }
+    }
+}
+
+impl world::System for WeaponSystem {
+
+    fn update(&mut self, control_state: &world::ControlState, components: &mut world::Components, entities: &mut Vec<world::Entity>) {
+
+        let now = time::precise_time_s();
+
+        let mut shake = 0.0;
+
+        for entity in entities.iter() {
+            if let (Some(weapon_id), Some(position_id)) = (entity.weapon, entity.position) {
+
+                let weapon = components.weapon.get_mut(weapon_id);
+                let position = components.position.get(position_id).clone();
+                let definition = weapon.definition();
+
+                let dt = (now - weapon.last_update) as f32;
+                weapon.last_update = now;
+
+                weapon.heat = (weapon.heat - definition.cooling_rate * dt).max(0.0);
+                if weapon.heat == 0.0 {
+                    weapon.overheated = false;
+                }
+
+                let ready = !weapon.overheated && now >= weapon.next_fire_time;
+
+                // Damage scale of a volley to fire this frame, if any
+                let mut volley = None;
+
+                match definition.pattern {
+                    FirePattern::Single | FirePattern::Spread => {
+                        if weapon.firing && ready {
+                            volley = Some(1.0);
+                            weapon.next_fire_time = now + definition.fire_delay as f64;
+                        }
+                    },
+                    FirePattern::Burst => {
+                        if weapon.burst_remaining == 0 && weapon.firing && ready {
+                            weapon.burst_remaining = definition.burst_count;
+                        }
+                        if weapon.burst_remaining > 0 && now >= weapon.next_fire_time {
+                            volley = Some(1.0);
+                            weapon.burst_remaining -= 1;
+                            let delay = if weapon.burst_remaining > 0 { definition.burst_interval } else { definition.fire_delay };
+                            weapon.next_fire_time = now + delay as f64;
+                        }
+                    },
+                    FirePattern::Charge => {
+                        if weapon.firing {
+                            if weapon.charge_start.is_none() && ready {
+                                weapon.charge_start = Some(now);
+                            }
+                        } else if let Some(start) = weapon.charge_start {
+                            // Fire on release, stronger the longer it was held
+                            let charge = if definition.charge_time > 0.0 {
+                                ((now - start) as f32 / definition.charge_time).min(1.0)
+                            } else {
+                                1.0
+                            };
+                            volley = Some(1.0 + (definition.charge_damage - 1.0) * charge);
+                            weapon.charge_start = None;
+                            weapon.next_fire_time = now + definition.fire_delay as f64;
+                        }
+                    },
+                }
+
+                if let Some(damage_scale) = volley {
+
+                    weapon.heat += definition.heat_per_shot;
+                    if weapon.heat >= 1.0 {
+                        weapon.heat = 1.0;
+                        weapon.overheated = true;
+                        weapon.burst_remaining = 0;
+                    }
+
+                    let origin = [position.x, position.y];
+                    for i in 0..definition.projectiles {
+                        let direction = volley_direction(weapon.fire_direction, i, definition.projectiles, definition.spread);
+                        self.shots.push((definition.clone(), origin, direction, damage_scale));
+                    }
+
+                    if let Some(receiver_id) = entity.event_receiver {
+                        let muzzle = vec2_add(origin, vec2_scale(weapon.fire_direction, definition.projectile.muzzle_offset));
+                        let event_receiver = components.event_receiver.get_mut(receiver_id);
+                        event_receiver.post_event(world::Event::WeaponFired(muzzle, weapon.fire_direction));
+                        event_receiver.post_event(world::Event::PlaySound(definition.fire_sound, SoundParams::sfx(FIRE_PRIORITY)));
+                    }
+
+                    if definition.shake > shake {
+                        shake = definition.shake;
+                    }
+                }
+            }
+        }
+
+        if shake > 0.0 {
+            event::broadcast_event(world::Event::CameraShake(shake), components, entities);
+        }
+
+        update_bullets(&mut self.damageable, components, entities);
+
+        let pool_id = entities.iter().filter_map(|entity| entity.projectile_pool).next();
+
+        // Recycle expired bullets, keeping everything else in creation order
+        let mut i = 0;
+        while i < entities.len() {
+            let expired = match entities[i].bullet {
+                Some(bullet_id) => components.bullet.get(bullet_id).expired,
+                None => false,
+            };
+
+            if expired {
+                let entity = entities.remove(i);
+                if let Some(pool_id) = pool_id {
+                    components.projectile_pool.get_mut(pool_id).free.push(entity);
+                }
+            } else {
+                i += 1;
+            }
+        }
+
+        for (definition, origin, direction, damage_scale) in self.shots.drain() {
+            let recycled = match pool_id {
+                Some(pool_id) => components.projectile_pool.get_mut(pool_id).free.pop(),
+                None => None,
+            };
+
+            let (bullet, allocated) = spawn_projectile(recycled, &definition.projectile, origin, direction, damage_scale, components);
+
+            if let (true, Some(pool_id)) = (allocated, pool_id) {
+                components.projectile_pool.get_mut(pool_id).allocated += 1;
+            }
+
+            entities.push(bullet);
+        }
+    }
+
+    fn set_paused(&mut self, paused: bool, components: &mut world::Components, entities: &mut Vec<world::Entity>) {
+        if !paused {
+            let now = time::precise_time_s();
+            for entity in entities.iter() {
+                if let Some(weapon_id) = entity.weapon {
+                    components.weapon.get_mut(weapon_id).last_update = now;
+                }
+            }
+        }
+    }
+
+    fn render(&mut self, context: &Context, gl: &mut GlGraphics, components: &mut world::Components, entities: &mut Vec<world::Entity>) {
+        // not implemented
+    }
+}
+
+enum BulletAction {
+    Expire,
+    /// Keep moving through a target that was already pierced
+    PassThrough,
+    Pierce(Id<world::AABBCollider>),
+    /// Reflect x, reflect y
+    Bounce(bool, bool),
+}
+
+///
+/// Decide what a bullet does this frame, without touching any components
+///
+fn bullet_action(entity: &world::Entity, damageable: &Vec<Id<world::AABBCollider>>, components: &world::Components, entities: &Vec<world::Entity>) -> Option<BulletAction> {
+
+    let bullet = components.bullet.get(entity.bullet.unwrap());
+    let body = components.dynamic_body.get(entity.dynamic_body.unwrap());
+
+    if bullet.distance_remaining <= 0.0 {
+        return Some(BulletAction::Expire);
+    }
+
+    let event_receiver = components.event_receiver.get(entity.event_receiver.unwrap());
+
+    for event in event_receiver.event_queue.iter() {
+        if let world::Event::Collision(_, other_id) = *event {
+
+            if bullet.last_hit == Some(other_id) {
+                return Some(BulletAction::PassThrough);
+            }
+
+            let hit_damageable = damageable.contains(&other_id);
+
+            if hit_damageable && bullet.pierce_remaining > 0 {
+                return Some(BulletAction::Pierce(other_id));
+            }
+
+            if !hit_damageable && bullet.bounces_remaining > 0 {
+                // Reflect along whichever axes are blocked
+                let collider = components.collider.get(entity.collider.unwrap());
+                let position = components.position.get(entity.position.unwrap());
+                let step_x = world::Position { x: position.x + body.vx, y: position.y };
+                let step_y = world::Position { x: position.x, y: position.y + body.vy };
+                let blocked_x = physics::check_collision(collider, &step_x, entity, components, entities).is_some();
+                let blocked_y = physics::check_collision(collider, &step_y, entity, components, entities).is_some();
+
+                return Some(BulletAction::Bounce(blocked_x || !blocked_y, blocked_y || !blocked_x));
+            }
+
+            return Some(BulletAction::Expire);
+        }
+    }
+
+    None
+}
+
+///
+/// Handle range, piercing and bouncing, marking spent bullets as expired
+///
+fn update_bullets(damageable: &mut Vec<Id<world::AABBCollider>>, components: &mut world::Components, entities: &Vec<world::Entity>) {
+
+    // Colliders that can take damage, bullets pierce these instead of bouncing off
+    damageable.clear();
+    for entity in entities.iter() {
+        if let (Some(collider_id), Some(_)) = (entity.collider, entity.health) {
+            damageable.push(collider_id);
+        }
+    }
+
+    for entity in entities.iter() {
+        if let (Some(bullet_id), Some(_), Some(body_id), Some(position_id), Some(_)) =
+            (entity.bullet, entity.event_receiver, entity.dynamic_body, entity.position, entity.collider) {
+
+            let action = bullet_action(entity, damageable, components, entities);
+
+            let bullet = components.bullet.get_mut(bullet_id);
+            let body = components.dynamic_body.get_mut(body_id);
+
+            bullet.distance_remaining -= (body.vx * body.vx + body.vy * body.vy).sqrt();
+
+            match action {
+                Some(BulletAction::Expire) => bullet.expired = true,
+                Some(BulletAction::PassThrough) | Some(BulletAction::Pierce(_)) => {
+                    if let Some(BulletAction::Pierce(other_id)) = action {
+                        bullet.pierce_remaining -= 1;
+                        bullet.last_hit = Some(other_id);
+                    }
+                    // Physics stopped it against the target, push it through
+                    let position = components.position.get_mut(position_id);
+                    position.x += body.vx;
+                    position.y += body.vy;
+                },
+                Some(BulletAction::Bounce(flip_x, flip_y)) => {
+                    bullet.bounces_remaining -= 1;
+                    if flip_x {
+                        body.vx = -body.vx * body.restitution;
+                    }
+                    if flip_y {
+                        body.vy = -body.vy * body.restitution;
+                    }
+                    if let Some(sprite_id) = entity.sprite_renderer {
+                        let angle = body.vy.atan2(body.vx) as f64 * 180.0 / f64::consts::PI;
+                        components.sprite_renderer.get_mut(sprite_id).sprite.set_rotation(angle + 90.0);
+                    }
+                },
+                None => {},
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::mem;
+    use std::path::PathBuf;
+    use std::rc::Rc;
+    use opengl_graphics::Texture;
+    use id::Id;
+
+    use assets::{self, AssetManager};
+    use audio::{AudioBackend, SilentBackend};
+    use event::EventSystem;
+    use world::{self, System};
+    use super::{WeaponSystem, load_definitions};
+
+    const WARM_UP_VOLLEYS: usize = 10;
+    const VOLLEYS: usize = 500;
+
+    fn pool(world: &world::World) -> &world::ProjectilePool {
+        let pool_id = world.entities.iter().filter_map(|entity| entity.projectile_pool).next().unwrap();
+        world.data.projectile_pool.get(pool_id)
+    }
+
+    /// Every bullet entity, flying or parked in the pool
+    fn bullets(world: &world::World) -> Vec<&world::Entity> {
+        world.entities.iter()
+            .chain(pool(world).free.iter())
+            .filter(|entity| entity.bullet.is_some())
+            .collect()
+    }
+
+    fn bullet_ids(world: &world::World) -> Vec<Id<world::Bullet>> {
+        bullets(world).iter().map(|entity| entity.bullet.unwrap()).collect()
+    }
+
+    #[test]
+    fn firing_reuses_textures_and_pooled_bullets() {
+
+        let backend: Box<AudioBackend> = Box::new(SilentBackend::new());
+        let mut assets = AssetManager::with_root(PathBuf::from("tests/fixtures"), Rc::new(RefCell::new(backend)));
+
+        // Never uploaded, there's no GL context in tests
+        let handle = assets.insert_texture("Bullet.png", Texture::new(0, 8, 8));
+        let texture = assets.texture(handle);
+
+        let definitions = load_definitions("stress_weapon.cfg", &mut assets).unwrap();
+        let definition = definitions[0].clone();
+        assert!(assets::same_texture(&definition.projectile.texture.get(), &texture));
+
+        let mut world = world::World::new();
+
+        let mut weapon = world::Weapon::new(definitions);
+        weapon.firing = true;
+        weapon.fire_direction = [1.0, 0.0];
+
+        let mut shooter = world::empty_entity();
+        shooter.position = Some(world.data.position.add(world::Position { x: 0.0, y: 0.0 }));
+        shooter.weapon = Some(world.data.weapon.add(weapon));
+        shooter.event_receiver = Some(world.data.event_receiver.add(world::EventReceiver::new()));
+        world.entities.push(shooter);
+
+        let mut pool_entity = world::empty_entity();
+        pool_entity.projectile_pool = Some(world.data.projectile_pool.add(world::ProjectilePool::new()));
+        world.entities.push(pool_entity);
+
+        let control_state = world::ControlState::new();
+        let mut weapons = WeaponSystem::new();
+        let mut events = EventSystem;
+
+        for _ in 0..WARM_UP_VOLLEYS {
+            weapons.update(&control_state, &mut world.data, &mut world.entities);
+            events.update(&control_state, &mut world.data, &mut world.entities);
+        }
+
+        let warmed_up = pool(&world).allocated;
+        let warm_free = pool(&world).free.len();
+        let warm_ids = bullet_ids(&world);
+        assert!(warmed_up > 0);
+        assert_eq!(warm_ids.len(), warmed_up);
+
+        for _ in 0..VOLLEYS {
+            weapons.update(&control_state, &mut world.data, &mut world.entities);
+            events.update(&control_state, &mut world.data, &mut world.entities);
+        }
+
+        // The same bullets keep coming back out of the pool
+        assert_eq!(pool(&world).allocated, warmed_up);
+        assert_eq!(pool(&world).free.len(), warm_free);
+        let ids = bullet_ids(&world);
+        assert_eq!(ids.len(), warmed_up);
+        assert!(ids.iter().all(|id| warm_ids.contains(id)));
+
+        // All drawn with the one texture the AssetManager loaded
+        for entity in bullets(&world).iter() {
+            let sprite = &world.data.sprite_renderer.get(entity.sprite_renderer.unwrap()).sprite;
+            assert!(assets::same_texture(sprite.get_texture(), &texture));
+        }
+
+        // Dropping textures calls into GL
+        mem::forget(world);
+        mem::forget(weapons);
+        mem::forget(definition);
+        mem::forget(texture);
+        mem::forget(assets);
+    }
+}
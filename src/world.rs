@@ -7,7 +7,11 @@ use id;
 use id::Id;
 use vecmath::*;
 
-use openal::al;
+use units;
+use assets::SharedTexture;
+use audio::SoundId;
+use mixer::{SoundParams, VolumeGroup};
+use music::MusicTrack;
 
 /// TODO move
 #[derive(Clone, Debug, PartialEq)]
@@ -33,6 +37,11 @@ impl SpriteRenderer {
     pub fn from_texture_region(texture: Rc<Texture>, region: [i32; 4]) -> SpriteRenderer {
         let mut sprite = Sprite::from_texture(texture.clone());
         sprite.set_src_rect(region);
+
+        // Sprites are sized in texture pixels, scale them down to world units
+        let scale = 1.0 / units::pixels_per_unit() as f64;
+        sprite.set_scale(scale, scale);
+
         SpriteRenderer { sprite: sprite }
     }
 }
@@ -67,10 +76,22 @@ pub enum PlayerState {
     OnRightWall,
 }
 
+///
+/// Swinging round an outer corner, from the surface that ended onto its side
+///
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct CornerTurn {
+    pub start: Vector2<f32>,
+    pub end: Vector2<f32>,
+    /// Center of the quarter circle from start to end
+    pub pivot: Vector2<f32>,
+    /// Updates done so far
+    pub progress: u32,
+}
+
 pub struct PlayerController {
     pub move_speed: f32,
     pub state: PlayerState,
-    pub ground_check: AABBCollider,
 
     pub aim_direction: [f32; 2],
 
@@ -87,44 +108,407 @@ pub struct PlayerController {
     pub idle_anim_aim_up_forward: SpriteAnimation,
     pub idle_anim_aim_down_forward: SpriteAnimation,
 
-    pub jump_sound: al::Buffer,
-    pub land_sound: al::Buffer,
+    pub jump_sound: SoundId,
+    pub land_sound: SoundId,
+
+    /// Launch speed off a surface, world units per update
+    pub jump_speed: f32,
+    /// World units per update per update
+    pub gravity: f32,
+    pub max_fall_speed: f32,
+    /// Fraction of the launch speed kept when jump is released early
+    pub jump_cut: f32,
+    /// Seconds after leaving a surface that a jump still works
+    pub coyote_time: f32,
+    /// Seconds a jump pressed in the air is remembered for
+    pub jump_buffer: f32,
+
+    /// Pulls back towards the last surface outside of gravity zones
+    pub gravity_direction: Vector2<f32>,
+    /// Keys held when walking last turned a corner, and the way they're walking now
+    pub crawl_input: Vector2<f32>,
+    pub crawl_direction: Vector2<f32>,
+
+    pub coyote_until: f64,
+    pub jump_buffered_until: f64,
+    /// Still rising from a jump that hasn't been cut short
+    pub jumping: bool,
+
+    pub corner_turn: Option<CornerTurn>,
+    /// Degrees the sprite is drawn at, turned towards the surface a little each update
+    pub sprite_rotation: f64,
 }
 
 pub struct CameraTarget;
 
+pub struct Camera {
+    pub position: Vector2<f32>,
+    /// Virtual screen size in pixels
+    pub viewport: Vector2<f32>,
+
+    pub smoothing: f32,
+    pub dead_zone: [f32; 2],
+    pub look_ahead: f32,
+    pub bounds: Option<[f32; 4]>,
+
+    pub zoom: f32,
+    pub target_zoom: f32,
+    pub zoom_smoothing: f32,
+
+    pub shake_trauma: f32,
+    pub shake_decay: f32,
+    pub shake_magnitude: f32,
+    pub shake_time: f32,
+    pub shake_offset: Vector2<f32>,
+}
+
 pub struct DynamicBody {
+    /// World units per update
     pub vx: f32,
     pub vy: f32,
+    pub mass: f32,
+    /// Fraction of speed kept bouncing off things, 0.0 stops dead
+    pub restitution: f32,
+    /// Fraction of speed along a surface lost on hitting it
+    pub friction: f32,
+    /// Fraction of speed lost every update
+    pub drag: f32,
+    /// Multiple of physics::GRAVITY pulling on the body
+    pub gravity_scale: f32,
+    /// World units per update
+    pub max_speed: Option<f32>,
+    /// Moved only by whoever sets its velocity, never by gravity or collisions
+    pub kinematic: bool,
+    /// Collider the body is standing on, it's carried along when that moves
+    pub ground: Option<Id<AABBCollider>>,
 }
 
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub struct AABBCollider {
     pub width: f32,
     pub height: f32,
+    /// One of the physics::LAYER_* bits
+    pub layer: u32,
+    /// Layers this collides with, both colliders have to accept each other
+    pub mask: u32,
+    /// Never blocks movement, only reports what's inside it as a Trigger
+    pub sensor: bool,
 }
 
 pub struct AudioSource {
-    pub source: al::Source,
-    pub buffer: al::Buffer,
+    /// Sounds to start on the next AudioSystem update
+    pub pending: Vec<(SoundId, SoundParams)>,
 }
 
-pub struct Weapon {
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum FirePattern {
+    /// One volley per trigger interval
+    Single,
+    /// Several projectiles fanned out per volley
+    Spread,
+    /// A fixed number of volleys in quick succession per trigger
+    Burst,
+    /// Held to charge, fires on release
+    Charge,
+}
+
+///
+/// Everything needed to spawn a weapon's projectiles
+///
+pub struct ProjectilePrefab {
+    pub texture: SharedTexture,
+    pub region: [i32; 4],
+    /// Collider size in world units
+    pub size: f32,
+    pub layer: u32,
+    pub mask: u32,
+    /// World units per update
+    pub speed: f32,
+    /// Distance from the shooter projectiles are spawned at
+    pub muzzle_offset: f32,
+    pub damage: f32,
+    pub knockback: f32,
+    /// Distance travelled before the projectile expires
+    pub range: f32,
+    /// Number of damageable entities passed through
+    pub pierce: u32,
+    /// Number of times it bounces off static geometry
+    pub bounces: u32,
+    /// Fraction of speed kept on each bounce
+    pub restitution: f32,
+}
+
+///
+/// Weapon stats, loaded from data and shared by every weapon using them
+///
+pub struct WeaponDefinition {
+    pub name: String,
+    pub pattern: FirePattern,
+    /// Seconds between volleys, or between bursts
     pub fire_delay: f32,
-    pub bullet_speed: f32,
-    pub fire_timer: f32,
-    pub fire_sound: al::Buffer,
+    pub projectiles: u32,
+    /// Total angle projectiles are fanned across, in radians
+    pub spread: f32,
+    pub burst_count: u32,
+    pub burst_interval: f32,
+    /// Seconds to reach full charge
+    pub charge_time: f32,
+    /// Damage multiplier at full charge
+    pub charge_damage: f32,
+    pub heat_per_shot: f32,
+    /// Heat lost per second
+    pub cooling_rate: f32,
+    pub shake: f32,
+    pub fire_sound: SoundId,
+    pub projectile: ProjectilePrefab,
+}
+
+pub struct Weapon {
+    pub definitions: Vec<Rc<WeaponDefinition>>,
+    pub current: usize,
+
     pub firing: bool,
     pub fire_direction: Vector2<f32>,
+    pub next_fire_time: f64,
+    pub burst_remaining: u32,
+    pub charge_start: Option<f64>,
+
+    /// 0.0 is cold, weapon overheats at 1.0 and can't fire until fully cooled
+    pub heat: f32,
+    pub overheated: bool,
+    pub last_update: f64,
+}
+
+pub struct Bullet {
+    pub damage: f32,
+    /// Speed added to whatever the bullet hits, along the bullet's direction
+    pub knockback: f32,
+    pub distance_remaining: f32,
+    pub pierce_remaining: u32,
+    pub bounces_remaining: u32,
+    /// Last target pierced, so it isn't counted again while passing through
+    pub last_hit: Option<Id<AABBCollider>>,
+    pub expired: bool,
 }
 
-pub struct Bullet;
+pub struct Health {
+    pub current: f32,
+    pub max: f32,
+    /// Respawns left, entities without lives are removed when they die
+    pub lives: Option<u32>,
+
+    /// Seconds of invulnerability after taking damage or respawning
+    pub invulnerability: f32,
+    pub invulnerable_until: f64,
+    /// Seconds without control after taking damage
+    pub stun: f32,
+    pub stunned_until: f64,
+    pub flash_interval: f32,
+
+    pub dead: bool,
+    pub death_time: f64,
+    pub respawn_delay: f32,
+    /// Level start or last checkpoint
+    pub respawn_point: Vector2<f32>,
+
+    pub hurt_sound: Option<SoundId>,
+    pub death_sound: Option<SoundId>,
+    pub death_anim: Option<SpriteAnimation>,
+}
+
+pub struct Score {
+    pub points: u32,
+}
+
+pub struct Level {
+    pub name: String,
+    pub music: Option<MusicTrack>,
+    pub gravity_zones: Vec<GravityZone>,
+    /// Hives placed in the level, it's complete once they and their roaches are gone
+    pub hives: usize,
+    pub cleared: bool,
+}
 
+///
+/// Region of the level where gravity pulls one way, regardless of the last surface
+///
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct GravityZone {
+    /// Left, top, right, bottom in world units
+    pub bounds: [f32; 4],
+    pub direction: Vector2<f32>,
+    /// Multiple of the player's normal gravity
+    pub strength: f32,
+}
+
+/// Marks entities counted as roaches remaining
+pub struct Enemy {
+    /// World units per update, roaches scuttle towards the player
+    pub speed: f32,
+}
+
+///
+/// A wave of roaches from a hive, loaded from a data file
+///
+pub struct WaveDefinition {
+    pub name: String,
+    /// Roaches in the wave, None keeps spawning on a timer until the hive is destroyed
+    pub count: Option<u32>,
+    /// Seconds before the first roach, after the previous wave is wiped out
+    pub delay: f32,
+    /// Seconds between roaches
+    pub interval: f32,
+    /// Most roaches from the hive alive at once
+    pub max_alive: u32,
+    pub roach_health: f32,
+    /// World units per update
+    pub roach_speed: f32,
+    pub roach_texture: SharedTexture,
+    pub roach_hurt_sound: SoundId,
+    pub roach_death_sound: SoundId,
+}
+
+///
+/// Spawns roaches in waves until it's destroyed
+///
+pub struct Hive {
+    /// Played in order, the hive goes quiet after the last one
+    pub waves: Vec<Rc<WaveDefinition>>,
+    pub current: usize,
+    /// Roaches spawned so far in the current wave
+    pub spawned: u32,
+    /// Seconds until the next roach can spawn
+    pub timer: f32,
+    /// Colliders of this hive's roaches that are still alive
+    pub roaches: Vec<Id<AABBCollider>>,
+}
+
+///
+/// Bullet entities that have expired and can be respawned without
+/// allocating new components. Lives in the world so it's dropped with it.
+///
+pub struct ProjectilePool {
+    pub free: Vec<Entity>,
+    /// Number of bullet entities ever created, for checking the pool is being reused
+    pub allocated: usize,
+}
+
+///
+/// Moves a kinematic body along a path, e.g. a moving platform
+///
+pub struct PathFollower {
+    /// Waypoints in world units
+    pub points: Vec<Vector2<f32>>,
+    /// World units per update
+    pub speed: f32,
+    /// Go from the last point back to the first, rather than back along the path
+    pub looping: bool,
+    /// Index of the point being moved towards
+    pub target: usize,
+    pub forward: bool,
+}
+
+///
+/// Tile that can be shot away, looking more damaged as its Health runs down
+///
+pub struct Destructible {
+    /// Textures from least to most damaged, shown as health drops
+    pub damaged_textures: Vec<Rc<Texture>>,
+    /// Index into damaged_textures of the texture shown, None while undamaged
+    pub stage: Option<usize>,
+}
+
+///
+/// What a trigger does to things entering it, besides posting
+/// TriggerEnter/Stay/Exit events
+///
+#[derive(Clone, Debug, PartialEq)]
+pub enum TriggerAction {
+    /// Nothing built in, for anything watching the trigger's events
+    None,
+    /// New respawn point, posted as a Checkpoint event
+    Checkpoint(Vector2<f32>),
+    /// Ends the level
+    Exit,
+    /// Damage dealt every update while inside
+    Hazard(f32),
+    /// Replaces the level's music
+    Music(MusicTrack),
+    /// Shown on the HUD while the player is inside
+    Prompt(String),
+}
+
+///
+/// Sensor volume tracking the colliders inside it
+///
+pub struct Trigger {
+    pub action: TriggerAction,
+    /// Colliders inside as of the last update
+    pub inside: Vec<Id<AABBCollider>>,
+}
+
+///
+/// Shared description of how particles are spawned and how they look over their life
+///
+pub struct ParticleEffect {
+    /// Particles spawned per burst
+    pub count: u32,
+    /// Min and max lifetime in seconds
+    pub lifetime: [f32; 2],
+    /// Min and max initial speed in world units per second
+    pub speed: [f32; 2],
+    /// Max angle either side of the emit direction, in radians
+    pub spread: f32,
+    pub gravity: Vector2<f32>,
+    /// Size at start and end of life, in world units
+    pub size: [f32; 2],
+    pub start_color: [f32; 4],
+    pub end_color: [f32; 4],
+    pub texture: Option<SharedTexture>,
+    /// Texture regions played over the particle's life
+    pub frames: Vec<[i32; 4]>,
+}
+
+///
+/// Continuously spawns particles at the entity's position
+///
+pub struct ParticleEmitter {
+    pub effect: Rc<ParticleEffect>,
+    /// Particles per second
+    pub rate: f32,
+    pub direction: Vector2<f32>,
+    pub active: bool,
+    pub accumulator: f32,
+}
+
+#[derive(Clone, Copy)]
 pub enum Event {
-    Collision(Id<AABBCollider>, Id<AABBCollider>)
+    Collision(Id<AABBCollider>, Id<AABBCollider>),
+    CameraShake(f32),
+    CameraZoom(f32),
+    WeaponFired(Vector2<f32>, Vector2<f32>),
+    /// Amount and knockback velocity
+    Damage(f32, Vector2<f32>),
+    Died,
+    /// New respawn point
+    Checkpoint(Vector2<f32>),
+    GameOver,
+    LevelComplete,
+    /// Fire-and-forget sound, post to a single receiver
+    PlaySound(SoundId, SoundParams),
+    /// Trigger collider and the collider entering, inside or leaving it,
+    /// posted to both of their entities
+    TriggerEnter(Id<AABBCollider>, Id<AABBCollider>),
+    TriggerStay(Id<AABBCollider>, Id<AABBCollider>),
+    TriggerExit(Id<AABBCollider>, Id<AABBCollider>),
+    SetVolume(VolumeGroup, f32),
 }
 
 pub struct EventReceiver {
+    /// Events posted last frame, readable by every system this frame
     pub event_queue: Vec<Event>,
+    /// Events posted this frame
+    pub pending_events: Vec<Event>,
 }
 
 
@@ -135,12 +519,54 @@ pub struct EntityPrototype {
     sprite_animator: SpriteAnimator,
     player_controller: PlayerController,
     camera_target: CameraTarget,
+    camera: Camera,
     collider: AABBCollider,
     dynamic_body: DynamicBody,
     audio_source: AudioSource,
     weapon: Weapon,
     bullet: Bullet,
     event_receiver: EventReceiver,
+    particle_emitter: ParticleEmitter,
+    health: Health,
+    score: Score,
+    level: Level,
+    enemy: Enemy,
+    projectile_pool: ProjectilePool,
+    trigger: Trigger,
+    path_follower: PathFollower,
+    destructible: Destructible,
+    hive: Hive,
+}
+
+///
+/// Entity with no components, for tests to fill in the ones they need
+///
+#[cfg(test)]
+pub fn empty_entity() -> Entity {
+    Entity {
+        position: None,
+        sprite_renderer: None,
+        sprite_animator: None,
+        player_controller: None,
+        camera_target: None,
+        camera: None,
+        collider: None,
+        dynamic_body: None,
+        audio_source: None,
+        weapon: None,
+        bullet: None,
+        event_receiver: None,
+        particle_emitter: None,
+        health: None,
+        score: None,
+        level: None,
+        enemy: None,
+        projectile_pool: None,
+        trigger: None,
+        path_follower: None,
+        destructible: None,
+        hive: None,
+    }
 }
 
 pub struct ControlState {
@@ -153,9 +579,49 @@ pub struct ControlState {
     pub aim_right: bool,
     pub aim_up: bool,
     pub aim_down: bool,
+
+    pub jump: bool,
+
+    /// Only set for the update following the key press
+    pub jump_pressed: bool,
+    pub next_weapon: bool,
+    pub previous_weapon: bool,
+}
+
+impl ControlState {
+
+    /// Nothing held
+    pub fn new() -> ControlState {
+        ControlState {
+            move_left: false,
+            move_right: false,
+            move_up: false,
+            move_down: false,
+            aim_left: false,
+            aim_right: false,
+            aim_up: false,
+            aim_down: false,
+            jump: false,
+            jump_pressed: false,
+            next_weapon: false,
+            previous_weapon: false,
+        }
+    }
 }
 
 pub trait System {
     fn update(&mut self, &ControlState, &mut Components, &mut Vec<Entity>);
     fn render(&mut self, &Context, &mut GlGraphics, &mut Components, &mut Vec<Entity>);
+
+    ///
+    /// Screen space pass after the world has been rendered, in virtual pixels
+    /// with the origin at the top left
+    ///
+    fn render_ui(&mut self, context: &Context, gl: &mut GlGraphics, components: &mut Components, entities: &mut Vec<Entity>) { }
+
+    ///
+    /// Called when the simulation is frozen or resumed, systems keeping their own
+    /// clocks should reset them on resume so the pause isn't seen as one long frame
+    ///
+    fn set_paused(&mut self, paused: bool, components: &mut Components, entities: &mut Vec<Entity>) { }
 }